@@ -0,0 +1,65 @@
+//! Measures the throughput of the rate cache when it's used by a batch rate request.  The server runs batch lookups on a
+//! Rayon threadpool with 24 threads, so the same is done here.  `single_lock` uses a cache with a single shard, which
+//! behaves like the original cache behind one global `Mutex`, while `sharded` uses the default number of shards.
+//!
+//! Run with `cargo bench --bench rate_cache`.
+
+#![feature(test)]
+
+extern crate chrono;
+extern crate polo_dashboard_backend;
+extern crate rayon;
+extern crate test;
+
+use chrono::NaiveDateTime;
+use rayon::prelude::*;
+use test::Bencher;
+
+use polo_dashboard_backend::RateCache;
+use polo_dashboard_backend::cache::{DEFAULT_RATE_CACHE_CAPACITY, DEFAULT_SHARD_COUNT};
+use polo_dashboard_backend::db_query::{HistRateQueryResult, RateOptions};
+
+/// The number of rates in each simulated batch request
+const BATCH_SIZE: usize = 10000;
+/// Every `WRITE_INTERVAL`th rate of a batch is stored in the cache after being looked up, like a cache miss would be
+const WRITE_INTERVAL: usize = 10;
+
+fn batch() -> Vec<(String, NaiveDateTime)> {
+    let pairs = ["BTC/XMR", "BTC/ETH", "BTC/USD", "XMR/USD", "BTC/DOGE"];
+    (0..BATCH_SIZE)
+        .map(|i| (String::from(pairs[i % pairs.len()]), NaiveDateTime::from_timestamp(1483228800 + (i as i64 * 60), 0)))
+        .collect()
+}
+
+/// Looks up a whole batch of rates in parallel on 24 threads, storing every `WRITE_INTERVAL`th one again so that the
+/// benchmark includes writes as well as reads.
+fn bench_batch(b: &mut Bencher, cache: RateCache) {
+    let pool = rayon::ThreadPool::new(rayon::Configuration::new().num_threads(24)).unwrap();
+    let requests: Vec<(usize, (String, NaiveDateTime))> = batch().into_iter().enumerate().collect();
+    let options = RateOptions::default();
+    let result = Some(HistRateQueryResult { rate: 0.01, minutes_ago: 1000000, legs: Vec::new() });
+    for &(_, (ref pair, timestamp)) in &requests {
+        cache.set(pair.clone(), result.clone(), timestamp, options);
+    }
+
+    b.iter(|| {
+        pool.install(|| {
+            requests.par_iter().for_each(|&(i, (ref pair, timestamp))| {
+                let cached = cache.get(pair.clone(), timestamp, options);
+                if i % WRITE_INTERVAL == 0 {
+                    cache.set(pair.clone(), cached.unwrap_or(None), timestamp, options);
+                }
+            });
+        });
+    });
+}
+
+#[bench]
+fn single_lock(b: &mut Bencher) {
+    bench_batch(b, RateCache::with_shards(DEFAULT_RATE_CACHE_CAPACITY, 1));
+}
+
+#[bench]
+fn sharded(b: &mut Bencher) {
+    bench_batch(b, RateCache::with_shards(DEFAULT_RATE_CACHE_CAPACITY, DEFAULT_SHARD_COUNT));
+}
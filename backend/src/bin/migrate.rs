@@ -0,0 +1,135 @@
+//! Copies the contents of the legacy per-pair `trades_<base>_<quote>` tables into the normalized `rates` table that is used
+//! by the backend.  The legacy tables are left untouched; they can be dropped once the migration has been verified.
+//!
+//! When built with the `sqlite` feature, `migrate --sqlite <path>` instead copies the normalized tables into a SQLite file
+//! that the backend can read rates from without a MySQL server.  When built with the `postgres` feature,
+//! `migrate --postgres <url>` copies them into a PostgreSQL database along with the ingestion checkpoints so that the
+//! prefiller can continue downloading into it.
+
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+extern crate chrono;
+extern crate polo_dashboard_backend;
+
+use std::env;
+use std::process;
+
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+use chrono::{Duration, NaiveDateTime};
+use polo_dashboard_backend::DbPool;
+use polo_dashboard_backend::config::Config;
+use polo_dashboard_backend::db_query::create_db_pool;
+use polo_dashboard_backend::normalized::migrate_trade_tables;
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+use polo_dashboard_backend::registry::PairMetadata;
+
+/// Calls the supplied function with the observations of the given pair stored in MySQL, one month at a time rather than
+/// all at once.  Returns the number of observations.
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+fn copy_rates<F>(pool: &DbPool, pair: &PairMetadata, mut store: F) -> Result<usize, String>
+    where F: FnMut(&[(NaiveDateTime, f32)]) -> Result<(), String>
+{
+    use polo_dashboard_backend::store::RateStore;
+
+    let (mut start, last) = match (pair.first_observation, pair.last_observation) {
+        (Some(first), Some(last)) => (first, last),
+        _ => { return Ok(0); },
+    };
+
+    let mut count = 0;
+    while start <= last {
+        let end = start + Duration::days(30);
+        let observations = pool.range(pair.id, start, end)?;
+        store(&observations)?;
+        count += observations.len();
+        start = end + Duration::seconds(1);
+    }
+    println!("Copied {} rates of {}.", count, pair.pair);
+
+    Ok(count)
+}
+
+#[cfg(feature = "sqlite")]
+fn export_sqlite(pool: &DbPool, path: &str) -> Result<usize, String> {
+    use polo_dashboard_backend::sqlite::SqliteStore;
+    use polo_dashboard_backend::store::RateStore;
+
+    let sqlite_store = SqliteStore::open(path)?;
+    let mut total = 0;
+    for pair in pool.list_pairs()? {
+        sqlite_store.register_pair(&pair)?;
+        total += copy_rates(pool, &pair, |observations| sqlite_store.insert_rates(pair.id, observations))?;
+    }
+
+    Ok(total)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn export_sqlite(_: &DbPool, _: &str) -> Result<usize, String> {
+    Err(String::from("The migration must be built with the `sqlite` feature to copy rates into SQLite."))
+}
+
+#[cfg(feature = "postgres")]
+fn export_postgres(pool: &DbPool, url: &str) -> Result<usize, String> {
+    use polo_dashboard_backend::postgres::PgStore;
+    use polo_dashboard_backend::prefiller::TradeStore;
+    use polo_dashboard_backend::store::RateStore;
+
+    // rates are copied one window at a time, so a single connection is enough
+    let pg_store = PgStore::connect(url, 1)?;
+    pg_store.create_tables()?;
+    let mut total = 0;
+    for pair in pool.list_pairs()? {
+        let name = format!("{}_{}", pair.base, pair.quote);
+        pg_store.register_pair(&name)?;
+        total += copy_rates(pool, &pair, |observations| pg_store.insert_rates(&name, observations))?;
+    }
+    for checkpoint in pool.list_checkpoints()? {
+        pg_store.save_checkpoint(&checkpoint)?;
+    }
+
+    Ok(total)
+}
+
+#[cfg(not(feature = "postgres"))]
+fn export_postgres(_: &DbPool, _: &str) -> Result<usize, String> {
+    Err(String::from("The migration must be built with the `postgres` feature to copy rates into PostgreSQL."))
+}
+
+fn main() {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            println!("Invalid configuration: {}", err);
+            process::exit(1);
+        },
+    };
+    let pool = DbPool(create_db_pool(&config.database));
+
+    let args: Vec<String> = env::args().collect();
+    if args.len() == 3 && (args[1] == "--sqlite" || args[1] == "--postgres") {
+        let exported = if args[1] == "--sqlite" {
+            export_sqlite(&pool, &args[2])
+        } else {
+            export_postgres(&pool, &args[2])
+        };
+        match exported {
+            Ok(total) => println!("Successfully copied {} rates.", total),
+            Err(err) => {
+                println!("Error while copying rates: {}", err);
+                process::exit(1);
+            },
+        }
+        return;
+    }
+
+    match migrate_trade_tables(&*pool.get_conn()) {
+        Ok(copied) => {
+            let total: usize = copied.iter().map(|&(_, count)| count).sum();
+            println!("Successfully copied {} rows from {} tables.", total, copied.len());
+        },
+        Err(err) => {
+            println!("Error while migrating trade tables: {}", err);
+            process::exit(1);
+        },
+    }
+}
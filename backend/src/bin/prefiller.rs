@@ -0,0 +1,228 @@
+//! Native replacement for the NodeJS prefiller utility.  Downloads the full trade history of all pairs listed on Poloniex
+//! into the database configured in `polo.toml`.  See README.md for more information.
+//!
+//! Run with `--status` to print the download status of every pair instead of downloading data.
+//!
+//! Run with `--gaps [--threshold-hours <hours>] [--queue]` to report stretches of time without any stored observations,
+//! optionally queueing them to be downloaded again.  Run with `--backfill` to download all queued gaps.
+//!
+//! Candles are brought up to date after every download.  Run with `--candles` to only update the candles.
+//!
+//! If the backend was built with the `postgres` feature and `rates.store` is set to the URL of a PostgreSQL database, data
+//! is downloaded into that database instead.  Only `--status` is supported in that case since gaps and candles are only
+//! tracked in MySQL.  Data can't be downloaded into any other store.
+
+extern crate chrono;
+extern crate polo_dashboard_backend;
+
+use std::env;
+use std::process;
+
+use chrono::Duration;
+use polo_dashboard_backend::DbPool;
+use polo_dashboard_backend::candles;
+use polo_dashboard_backend::config::Config;
+use polo_dashboard_backend::db_query::create_db_pool;
+use polo_dashboard_backend::gaps::{self, DEFAULT_GAP_THRESHOLD_HOURS};
+#[cfg(feature = "postgres")]
+use polo_dashboard_backend::postgres::PgStore;
+use polo_dashboard_backend::prefiller::{PoloClient, Prefiller, TradeStore, COINBASE_API_URL, POLONIEX_API_URL};
+
+/// Prints which pairs have been completely downloaded and where incomplete downloads will be resumed.
+fn print_status(store: &TradeStore) {
+    let checkpoints = match store.create_tables().and_then(|_| store.list_checkpoints()) {
+        Ok(checkpoints) => checkpoints,
+        Err(err) => {
+            println!("Error while loading ingestion checkpoints: {}", err);
+            process::exit(1);
+        },
+    };
+
+    let complete_count = checkpoints.iter().filter(|checkpoint| checkpoint.complete).count();
+    for checkpoint in &checkpoints {
+        if checkpoint.complete {
+            println!("{}: complete through {} (updated {})", checkpoint.pair, checkpoint.max_end, checkpoint.updated_at);
+        } else {
+            println!(
+                "{}: incomplete; resumes at segment {} : {}{} (updated {})",
+                checkpoint.pair, checkpoint.segment_start, checkpoint.segment_end,
+                if checkpoint.backtracking { " while backtracking" } else { "" }, checkpoint.updated_at
+            );
+        }
+    }
+    println!("{} of {} pairs complete.", complete_count, checkpoints.len());
+}
+
+/// Reports all gaps in the stored data that are longer than the threshold, optionally queueing them to be backfilled.
+fn scan_gaps(pool: &DbPool, threshold_hours: i64, queue: bool) -> Result<(), String> {
+    let conn = &*pool.get_conn();
+    let found = gaps::scan_all(Duration::hours(threshold_hours), conn)?;
+    if queue {
+        gaps::create_backfill_queue_table(conn)?;
+    }
+
+    for gap in &found {
+        println!("{}: no observations from {} to {} ({} hours)", gap.pair, gap.start, gap.end, gap.duration().num_hours());
+        if !queue {
+            continue;
+        }
+
+        if gaps::is_fiat_pair(&gap.pair) {
+            println!("  Not queueing gap since fiat data can't be backfilled from Poloniex.");
+        } else if gaps::queue_backfill(gap, conn)? {
+            println!("  Queued gap to be backfilled.");
+        }
+    }
+    println!("Found {} gaps longer than {} hours.", found.len(), threshold_hours);
+
+    Ok(())
+}
+
+/// Downloads all windows in the backfill queue again.  Returns `false` if some of them failed to download; those stay
+/// queued so that the next backfill retries them.
+fn backfill(prefiller: &Prefiller<DbPool>) -> Result<bool, String> {
+    let conn = &*prefiller.store.get_conn();
+    gaps::create_backfill_queue_table(conn)?;
+
+    let failed = gaps::run_backfills(gaps::pending_backfills(conn)?, |request| {
+        prefiller.download_window(&request.pair, request.gap_start.timestamp() + 1, request.gap_end.timestamp() - 1)
+            .map_err(|err| format!("{:?}", err))
+    }, conn)?;
+    for &(ref request, ref err) in &failed {
+        println!("Failed to backfill {} from {} to {}: {}", request.pair, request.gap_start, request.gap_end, err);
+    }
+
+    Ok(failed.is_empty())
+}
+
+/// Builds all candles that are missing or may have changed because of newly downloaded data.
+fn update_candles(pool: &DbPool) {
+    if let Err(err) = candles::update_all(&*pool.get_conn()) {
+        println!("Error while updating candles: {}", err);
+        process::exit(1);
+    }
+}
+
+/// Creates a client for the Poloniex and Coinbase APIs.  The API URLs can be overridden in order to run against a mock
+/// server.
+fn api_client() -> PoloClient {
+    let poloniex_url = env::var("POLONIEX_API_URL").unwrap_or(String::from(POLONIEX_API_URL));
+    let coinbase_url = env::var("COINBASE_API_URL").unwrap_or(String::from(COINBASE_API_URL));
+    PoloClient::new(&poloniex_url, &coinbase_url)
+}
+
+/// Downloads the trade history of every pair into the prefiller's store.  Returns `false` if some of the pairs failed
+/// to download.
+fn download<S: TradeStore>(prefiller: &Prefiller<S>) -> bool {
+    match prefiller.run() {
+        Ok(ref failed) if failed.is_empty() => {
+            println!("Successfully finished downloading all data.");
+            true
+        },
+        Ok(failed) => {
+            println!("Finished downloading data, but failed to download the following pairs: {:?}", failed);
+            false
+        },
+        Err(err) => {
+            println!("Error while attempting to fetch Poloniex currency data: {:?}", err);
+            process::exit(1);
+        },
+    }
+}
+
+#[cfg(feature = "postgres")]
+fn run_postgres(config: &Config, args: &[String]) {
+    let store = match PgStore::connect(&config.rates.store, config.database.pool_size) {
+        Ok(store) => store,
+        Err(err) => {
+            println!("Error while connecting to PostgreSQL: {}", err);
+            process::exit(1);
+        },
+    };
+    if args.iter().any(|arg| arg == "--status") {
+        print_status(&store);
+        return;
+    }
+    if args.iter().any(|arg| arg == "--gaps" || arg == "--backfill" || arg == "--candles") {
+        println!("Gaps and candles are only supported when data is stored in MySQL.");
+        process::exit(1);
+    }
+
+    if !download(&Prefiller::new(api_client(), store)) {
+        process::exit(1);
+    }
+}
+
+#[cfg(not(feature = "postgres"))]
+fn run_postgres(_: &Config, _: &[String]) {
+    println!("The prefiller must be built with the `postgres` feature to store data in PostgreSQL.");
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            println!("Invalid configuration: {}", err);
+            process::exit(1);
+        },
+    };
+    if config.rates.store.starts_with("postgres://") || config.rates.store.starts_with("postgresql://") {
+        run_postgres(&config, &args);
+        return;
+    }
+    if !config.uses_mysql() {
+        println!("The prefiller can only store data in MySQL or PostgreSQL, but `rates.store` is {:?}.", config.rates.store);
+        process::exit(1);
+    }
+    let database = &config.database;
+
+    if args.iter().any(|arg| arg == "--status") {
+        print_status(&DbPool(create_db_pool(database)));
+        return;
+    }
+
+    if args.iter().any(|arg| arg == "--gaps") {
+        let threshold_hours = match args.iter().position(|arg| arg == "--threshold-hours") {
+            Some(i) => args.get(i + 1)
+                .and_then(|hours| hours.parse().ok())
+                .expect("`--threshold-hours` must be followed by a number of hours!"),
+            None => DEFAULT_GAP_THRESHOLD_HOURS,
+        };
+        let queue = args.iter().any(|arg| arg == "--queue");
+        if let Err(err) = scan_gaps(&DbPool(create_db_pool(database)), threshold_hours, queue) {
+            println!("Error while scanning for gaps: {}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if args.iter().any(|arg| arg == "--candles") {
+        update_candles(&DbPool(create_db_pool(database)));
+        return;
+    }
+
+    let prefiller = Prefiller::new(api_client(), DbPool(create_db_pool(database)));
+
+    if args.iter().any(|arg| arg == "--backfill") {
+        let success = match backfill(&prefiller) {
+            Ok(success) => success,
+            Err(err) => {
+                println!("Error while backfilling: {}", err);
+                process::exit(1);
+            },
+        };
+        update_candles(&prefiller.store);
+        if !success {
+            process::exit(1);
+        }
+        return;
+    }
+
+    let success = download(&prefiller);
+    update_candles(&prefiller.store);
+    if !success {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,321 @@
+//! Bounded in-memory cache for historical rates.  Since historical exchange rates don't change, rates can safely be cached
+//! to avoid extra database load.  The cache holds a limited number of entries and evicts the least recently used entry
+//! once it's full so that the memory usage of a long-running server doesn't grow with every distinct request.
+//!
+//! Batch requests look up rates in parallel, so the cache is split into shards that are locked independently of each
+//! other.  Each shard evicts its own least recently used entry, which approximates a cache-wide LRU policy.
+//!
+//! The cache can be snapshotted to a file and reloaded from it so that its contents survive restarts of the server.
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::sync::{Arc, Mutex};
+
+use chrono::NaiveDateTime;
+use serde_json;
+
+use super::debug;
+use db_query::{HistRateQueryResult, RateOptions};
+
+/// The number of rates that are cached if no capacity is configured
+pub const DEFAULT_RATE_CACHE_CAPACITY: usize = 250000;
+/// The number of independently locked shards that the cache is split into, which should comfortably exceed the number of
+/// Rayon threads looking up rates at once
+pub const DEFAULT_SHARD_COUNT: usize = 64;
+
+/// The header written at the start of every snapshot file.  The version must be incremented whenever the serialized form
+/// of `SnapshotEntry` or any of the types that it contains changes so that incompatible snapshots are discarded.
+const SNAPSHOT_HEADER: &'static str = "polo-rate-cache-snapshot 3";
+
+/// Rates are keyed by the options used to resolve them as well since different modes and search radii produce different
+/// rates for the same pair and timestamp.
+type RateKey = (String, NaiveDateTime, RateOptions);
+
+/// Counters describing how effective the cache has been since the server was started
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RateCacheStats {
+    pub capacity: usize,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+/// A single cached rate as it's stored in a snapshot file, with one entry per line
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    pair: String,
+    timestamp: NaiveDateTime,
+    options: RateOptions,
+    result: Option<HistRateQueryResult>,
+}
+
+struct CacheState {
+    /// The cached rates along with the tick at which they were last used
+    entries: HashMap<RateKey, (Option<HistRateQueryResult>, u64)>,
+    /// The keys of all cached rates ordered from least to most recently used
+    recency: BTreeMap<u64, RateKey>,
+    /// Incremented every time an entry is used
+    tick: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl CacheState {
+    fn new() -> CacheState {
+        CacheState {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    /// Marks the entry with the given key as the most recently used one.
+    fn touch(&mut self, key: &RateKey) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.recency.remove(&entry.1);
+            self.recency.insert(tick, key.clone());
+            entry.1 = tick;
+        }
+    }
+
+    fn set(&mut self, key: RateKey, rate: Option<HistRateQueryResult>, capacity: usize) {
+        if self.entries.contains_key(&key) {
+            if let Some(entry) = self.entries.get_mut(&key) {
+                entry.0 = rate;
+            }
+            self.touch(&key);
+            return;
+        }
+
+        while self.entries.len() >= capacity {
+            let oldest = match self.recency.keys().next() {
+                Some(&tick) => tick,
+                None => break,
+            };
+            if let Some(evicted) = self.recency.remove(&oldest) {
+                self.entries.remove(&evicted);
+                self.evictions += 1;
+            }
+        }
+
+        self.tick += 1;
+        let tick = self.tick;
+        self.recency.insert(tick, key.clone());
+        self.entries.insert(key, (rate, tick));
+    }
+
+    fn get(&mut self, key: &RateKey) -> Option<Option<HistRateQueryResult>> {
+        let cached = self.entries.get(key).map(|entry| entry.0.clone());
+        match cached {
+            Some(_) => {
+                self.hits += 1;
+                self.touch(key);
+            },
+            None => { self.misses += 1; },
+        }
+
+        cached
+    }
+}
+
+/// A sharded, least recently used cache of rates pulled from the database.  Clones of the cache share its contents.
+#[derive(Clone)]
+pub struct RateCache {
+    /// The maximum number of rates held by each shard
+    shard_capacity: usize,
+    shards: Arc<Vec<Mutex<CacheState>>>,
+}
+
+impl RateCache {
+    /// Creates a cache holding at most `capacity` rates split across `DEFAULT_SHARD_COUNT` shards.  A capacity of zero
+    /// disables caching.
+    pub fn new(capacity: usize) -> RateCache {
+        RateCache::with_shards(capacity, DEFAULT_SHARD_COUNT)
+    }
+
+    /// Creates a cache holding about `capacity` rates split across the given number of shards.  The capacity is rounded up
+    /// to a multiple of the number of shards.  With a single shard, the cache is a strict LRU cache behind one lock.
+    pub fn with_shards(capacity: usize, shard_count: usize) -> RateCache {
+        let shard_count = cmp::max(shard_count, 1);
+        RateCache {
+            shard_capacity: (capacity + shard_count - 1) / shard_count,
+            shards: Arc::new((0..shard_count).map(|_| Mutex::new(CacheState::new())).collect()),
+        }
+    }
+
+    /// Returns the shard that holds the rate with the given key.
+    fn shard(&self, key: &RateKey) -> &Mutex<CacheState> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[(hasher.finish() % self.shards.len() as u64) as usize]
+    }
+
+    /// Inserts an exchange rate into the cache, evicting the least recently used rate of its shard if the shard is full.
+    pub fn set(&self, pair: String, rate: Option<HistRateQueryResult>, timestamp: NaiveDateTime, options: RateOptions) {
+        if self.shard_capacity == 0 {
+            return;
+        }
+
+        let key = (pair, timestamp, options);
+        self.shard(&key).lock().unwrap().set(key, rate, self.shard_capacity);
+    }
+
+    /// Attempts to retrieve a cached value, marking it as recently used if it's found.
+    pub fn get(&self, pair: String, timestamp: NaiveDateTime, options: RateOptions) -> Option<Option<HistRateQueryResult>> {
+        let key = (pair, timestamp, options);
+        self.shard(&key).lock().unwrap().get(&key)
+    }
+
+    pub fn stats(&self) -> RateCacheStats {
+        let mut stats = RateCacheStats {
+            capacity: self.shard_capacity * self.shards.len(),
+            entries: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        };
+        for shard in self.shards.iter() {
+            let state = shard.lock().unwrap();
+            stats.entries += state.entries.len();
+            stats.hits += state.hits;
+            stats.misses += state.misses;
+            stats.evictions += state.evictions;
+        }
+
+        stats
+    }
+
+    /// Writes all cached rates to the file at the given path, replacing it once the snapshot is complete.  Returns the
+    /// number of rates that were written.
+    pub fn save_snapshot(&self, path: &str) -> Result<usize, String> {
+        let temp_path = format!("{}.tmp", path);
+        let mut writer = BufWriter::new(File::create(&temp_path).map_err(debug)?);
+        writeln!(writer, "{}", SNAPSHOT_HEADER).map_err(debug)?;
+
+        let mut count = 0;
+        for shard in self.shards.iter() {
+            let state = shard.lock().unwrap();
+            // rates are written from least to most recently used so that the most recently used ones are kept if the
+            // snapshot is loaded into a smaller cache
+            for key in state.recency.values() {
+                let entry = SnapshotEntry {
+                    pair: key.0.clone(),
+                    timestamp: key.1,
+                    options: key.2,
+                    result: state.entries[key].0.clone(),
+                };
+                writeln!(writer, "{}", serde_json::to_string(&entry).map_err(debug)?).map_err(debug)?;
+                count += 1;
+            }
+        }
+
+        writer.flush().map_err(debug)?;
+        fs::rename(&temp_path, path).map_err(debug)?;
+        Ok(count)
+    }
+
+    /// Loads all rates from the snapshot file at the given path into the cache.  Snapshots that don't exist or were
+    /// written by an incompatible version are ignored.  Returns the number of rates that were loaded.
+    pub fn load_snapshot(&self, path: &str) -> Result<usize, String> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => { return Ok(0); },
+            Err(err) => { return Err(debug(err)); },
+        };
+
+        let mut lines = BufReader::new(file).lines();
+        match lines.next() {
+            Some(Ok(ref header)) if header == SNAPSHOT_HEADER => (),
+            _ => {
+                println!("Discarding rate cache snapshot {} since it was written by an incompatible version.", path);
+                return Ok(0);
+            },
+        }
+
+        let mut count = 0;
+        for line in lines {
+            let entry: SnapshotEntry = serde_json::from_str(&line.map_err(debug)?).map_err(debug)?;
+            self.set(entry.pair, entry.result, entry.timestamp, entry.options);
+            count += 1;
+        }
+
+        Ok(count)
+    }
+}
+
+#[test]
+fn test_lru_eviction() {
+    let time = |minutes: i64| NaiveDateTime::from_timestamp(1483228800 + (minutes * 60), 0);
+    let rate = |rate: f32| Some(HistRateQueryResult { rate: rate, minutes_ago: 1000, legs: Vec::new() });
+    let options = RateOptions::default();
+
+    let cache = RateCache::with_shards(2, 1);
+    cache.set(String::from("BTC/XMR"), rate(1.), time(0), options);
+    cache.set(String::from("BTC/XMR"), rate(2.), time(1), options);
+    // using the first rate makes the second one the least recently used
+    assert_eq!(cache.get(String::from("BTC/XMR"), time(0), options), Some(rate(1.)));
+    cache.set(String::from("BTC/XMR"), None, time(2), options);
+
+    assert_eq!(cache.get(String::from("BTC/XMR"), time(1), options), None);
+    assert_eq!(cache.get(String::from("BTC/XMR"), time(0), options), Some(rate(1.)));
+    assert_eq!(cache.get(String::from("BTC/XMR"), time(2), options), Some(None));
+    assert_eq!(cache.stats(), RateCacheStats { capacity: 2, entries: 2, hits: 3, misses: 1, evictions: 1 });
+
+    let disabled = RateCache::new(0);
+    disabled.set(String::from("BTC/XMR"), rate(1.), time(0), options);
+    assert_eq!(disabled.get(String::from("BTC/XMR"), time(0), options), None);
+}
+
+#[test]
+fn test_sharded_capacity() {
+    let options = RateOptions::default();
+    let cache = RateCache::with_shards(100, 8);
+    for minutes in 0..1000 {
+        cache.set(String::from("BTC/XMR"), None, NaiveDateTime::from_timestamp(1483228800 + (minutes * 60), 0), options);
+    }
+
+    let stats = cache.stats();
+    assert_eq!(stats.capacity, 104);
+    assert!(stats.entries <= stats.capacity);
+    assert_eq!(stats.entries as u64 + stats.evictions, 1000);
+}
+
+#[test]
+fn test_snapshot_round_trip() {
+    use std::env;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let time = |minutes: i64| NaiveDateTime::from_timestamp(1483228800 + (minutes * 60), 0);
+    let options = RateOptions::default();
+    // concurrent test runs mustn't share a snapshot file
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let path = env::temp_dir().join(format!("polo-rate-cache-test-{}-{}.snapshot", now.as_secs(), now.subsec_nanos()));
+    let path = path.to_str().unwrap();
+
+    let cache = RateCache::new(100);
+    let result = Some(HistRateQueryResult { rate: 0.5, minutes_ago: 1000, legs: Vec::new() });
+    cache.set(String::from("BTC/XMR"), result.clone(), time(0), options);
+    cache.set(String::from("BTC/XMR"), None, time(1), options);
+    assert_eq!(cache.save_snapshot(path), Ok(2));
+
+    let loaded = RateCache::new(100);
+    assert_eq!(loaded.load_snapshot(path), Ok(2));
+    assert_eq!(loaded.get(String::from("BTC/XMR"), time(0), options), Some(result));
+    assert_eq!(loaded.get(String::from("BTC/XMR"), time(1), options), Some(None));
+
+    // snapshots with a different version are discarded
+    File::create(path).unwrap().write_all(b"polo-rate-cache-snapshot 0\n").unwrap();
+    assert_eq!(RateCache::new(100).load_snapshot(path), Ok(0));
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,312 @@
+//! OHLC candles aggregated from the stored rates.  Candles are stored for a few fixed periods in the `candles` table and
+//! kept up to date by the prefiller; candles for longer periods are assembled from the stored ones when they're requested.
+
+use chrono::NaiveDateTime;
+use diesel;
+use diesel::prelude::*;
+use diesel::mysql::MysqlConnection;
+
+use super::debug;
+use normalized::{self, PairInfo};
+
+/// The periods, in seconds, for which candles are stored.  Each period must evenly divide the next one.
+pub const CANDLE_PERIODS: &[i64] = &[300, 3600, 86400];
+/// Number of observations loaded from the database at a time while building candles
+const BUILD_PAGE_SIZE: i64 = 100000;
+/// Number of rows inserted per `INSERT` statement to stay under MySQL's limit on the number of bound parameters
+const INSERT_BATCH_SIZE: usize = 5000;
+
+table! {
+    candles (pair_id, period, start_time) {
+        pair_id -> Integer,
+        period -> Integer,
+        start_time -> Timestamp,
+        open -> Float,
+        high -> Float,
+        low -> Float,
+        close -> Float,
+        observations -> Integer,
+    }
+}
+
+/// A candle as it is stored in the database
+#[derive(Clone, Debug, PartialEq, Queryable, Insertable)]
+#[table_name="candles"]
+pub struct StoredCandle {
+    pub pair_id: i32,
+    /// The length of the candle in seconds
+    pub period: i32,
+    pub start_time: NaiveDateTime,
+    pub open: f32,
+    pub high: f32,
+    pub low: f32,
+    pub close: f32,
+    /// The number of stored observations that the candle was built from
+    pub observations: i32,
+}
+
+/// A candle in the shape that TechanJS expects, with the date as a Unix timestamp in seconds like the Poloniex chart API
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Candle {
+    pub date: i64,
+    pub open: f32,
+    pub high: f32,
+    pub low: f32,
+    pub close: f32,
+    /// Trade volumes aren't stored since trades are downsampled before they're stored, so this is always `null`.
+    pub volume: Option<f64>,
+}
+
+/// Returns the start of the candle of the given period that contains the supplied Unix timestamp.
+fn candle_start(timestamp: i64, period: i64) -> i64 {
+    timestamp - (((timestamp % period) + period) % period)
+}
+
+/// Aggregates observations that are supplied in ascending order into candles of a single period.
+pub struct CandleBuilder {
+    pair_id: i32,
+    period: i64,
+    current: Option<StoredCandle>,
+    pub candles: Vec<StoredCandle>,
+}
+
+impl CandleBuilder {
+    pub fn new(pair_id: i32, period: i64) -> CandleBuilder {
+        CandleBuilder {
+            pair_id: pair_id,
+            period: period,
+            current: None,
+            candles: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, time: NaiveDateTime, rate: f32) {
+        let start_time = NaiveDateTime::from_timestamp(candle_start(time.timestamp(), self.period), 0);
+        if let Some(ref mut candle) = self.current {
+            if candle.start_time == start_time {
+                candle.high = candle.high.max(rate);
+                candle.low = candle.low.min(rate);
+                candle.close = rate;
+                candle.observations += 1;
+                return;
+            }
+        }
+
+        if let Some(candle) = self.current.take() {
+            self.candles.push(candle);
+        }
+        self.current = Some(StoredCandle {
+            pair_id: self.pair_id,
+            period: self.period as i32,
+            start_time: start_time,
+            open: rate,
+            high: rate,
+            low: rate,
+            close: rate,
+            observations: 1,
+        });
+    }
+
+    /// Returns all candles including the one that is still being built.
+    pub fn finish(mut self) -> Vec<StoredCandle> {
+        if let Some(candle) = self.current.take() {
+            self.candles.push(candle);
+        }
+        self.candles
+    }
+}
+
+/// Combines candles that are sorted from oldest to newest into candles of a longer period, which must be a multiple of
+/// their period.
+pub fn merge_candles(candles: &[StoredCandle], period: i64) -> Vec<Candle> {
+    let mut merged: Vec<Candle> = Vec::new();
+    for candle in candles {
+        let date = candle_start(candle.start_time.timestamp(), period);
+        if let Some(last) = merged.last_mut() {
+            if last.date == date {
+                last.high = last.high.max(candle.high);
+                last.low = last.low.min(candle.low);
+                last.close = candle.close;
+                continue;
+            }
+        }
+
+        merged.push(Candle {
+            date: date,
+            open: candle.open,
+            high: candle.high,
+            low: candle.low,
+            close: candle.close,
+            volume: None,
+        });
+    }
+
+    merged
+}
+
+/// Returns the longest stored period that evenly divides the requested period, if there is one.
+pub fn source_period(period: i64) -> Option<i64> {
+    CANDLE_PERIODS.iter().rev().find(|&&stored| period > 0 && period % stored == 0).cloned()
+}
+
+pub fn create_candles_table(conn: &MysqlConnection) -> Result<(), String> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS `candles` (
+            pair_id INT NOT NULL,
+            period INT NOT NULL,
+            start_time TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+            open FLOAT NOT NULL,
+            high FLOAT NOT NULL,
+            low FLOAT NOT NULL,
+            close FLOAT NOT NULL,
+            observations INT NOT NULL,
+            PRIMARY KEY (pair_id, period, start_time),
+            FOREIGN KEY (pair_id) REFERENCES pairs(id)
+        );"
+    ).map_err(debug)?;
+
+    Ok(())
+}
+
+/// Returns the time from which the candles of the given pair need to be rebuilt.  The last stored candle of each period
+/// may be incomplete, so everything from the start of the longest candle containing any of them is rebuilt.
+fn rebuild_start(pair_id: i32, conn: &MysqlConnection) -> Result<NaiveDateTime, String> {
+    use self::candles::dsl;
+
+    let mut start: Option<NaiveDateTime> = None;
+    for &period in CANDLE_PERIODS {
+        let latest: Option<NaiveDateTime> = dsl::candles
+            .filter(dsl::pair_id.eq(pair_id))
+            .filter(dsl::period.eq(period as i32))
+            .order(dsl::start_time.desc())
+            .select(dsl::start_time)
+            .first(conn)
+            .optional()
+            .map_err(debug)?;
+
+        start = match (start, latest) {
+            // nothing has been built for this period, so everything must be built
+            (_, None) => { return Ok(NaiveDateTime::from_timestamp(0, 0)); },
+            (Some(start), Some(latest)) => Some(start.min(latest)),
+            (None, Some(latest)) => Some(latest),
+        };
+    }
+
+    let longest = CANDLE_PERIODS[CANDLE_PERIODS.len() - 1];
+    Ok(NaiveDateTime::from_timestamp(candle_start(start.map(|start| start.timestamp()).unwrap_or(0), longest), 0))
+}
+
+/// Builds all candles of the given pair that don't exist yet or may have changed since they were last built.  Returns
+/// the number of candles that were stored.
+pub fn update_pair(pair: &PairInfo, conn: &MysqlConnection) -> Result<usize, String> {
+    use normalized::rates::dsl;
+
+    let start = rebuild_start(pair.id, conn)?;
+    let mut builders: Vec<CandleBuilder> = CANDLE_PERIODS.iter()
+        .map(|&period| CandleBuilder::new(pair.id, period))
+        .collect();
+
+    // page through the observations rather than loading all of them at once
+    let mut last = NaiveDateTime::from_timestamp(0, 0);
+    loop {
+        let observations: Vec<(NaiveDateTime, f32)> = dsl::rates
+            .filter(dsl::pair_id.eq(pair.id))
+            .filter(dsl::trade_time.ge(start))
+            .filter(dsl::trade_time.gt(last))
+            .order(dsl::trade_time.asc())
+            .select((dsl::trade_time, dsl::rate))
+            .limit(BUILD_PAGE_SIZE)
+            .load(conn)
+            .map_err(debug)?;
+
+        for &(time, rate) in &observations {
+            for builder in &mut builders {
+                builder.push(time, rate);
+            }
+        }
+        if (observations.len() as i64) < BUILD_PAGE_SIZE {
+            break;
+        }
+        if let Some(&(time, _)) = observations.last() {
+            last = time;
+        }
+    }
+
+    let built: Vec<StoredCandle> = builders.into_iter().flat_map(|builder| builder.finish()).collect();
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::delete(
+            candles::table
+                .filter(candles::pair_id.eq(pair.id))
+                .filter(candles::start_time.ge(start))
+        ).execute(conn)?;
+        for batch in built.chunks(INSERT_BATCH_SIZE) {
+            diesel::insert(batch).into(candles::table).execute(conn)?;
+        }
+        Ok(())
+    }).map_err(debug)?;
+
+    Ok(built.len())
+}
+
+/// Brings the candles of every registered pair up to date.
+pub fn update_all(conn: &MysqlConnection) -> Result<(), String> {
+    create_candles_table(conn)?;
+
+    for pair in normalized::list_pairs(conn)? {
+        let count = update_pair(&pair, conn)?;
+        println!("Stored {} candles for {}.", count, pair.name());
+    }
+
+    Ok(())
+}
+
+/// Returns the candles of the given pair and period that overlap the supplied range, oldest first.  The period must be a
+/// multiple of one of the stored periods.
+pub fn get_candles(
+    pair_id: i32, period: i64, start: NaiveDateTime, end: NaiveDateTime, conn: &MysqlConnection
+) -> Result<Vec<Candle>, String> {
+    use self::candles::dsl;
+
+    let stored_period = source_period(period)
+        .ok_or(format!("Candles aren't available for a period of {} seconds.", period))?;
+    let first_start = NaiveDateTime::from_timestamp(candle_start(start.timestamp(), period), 0);
+
+    let stored: Vec<StoredCandle> = dsl::candles
+        .filter(dsl::pair_id.eq(pair_id))
+        .filter(dsl::period.eq(stored_period as i32))
+        .filter(dsl::start_time.ge(first_start))
+        .filter(dsl::start_time.le(end))
+        .order(dsl::start_time.asc())
+        .load(conn)
+        .map_err(debug)?;
+
+    Ok(merge_candles(&stored, period))
+}
+
+#[test]
+fn test_candle_building() {
+    let time = |minutes: i64| NaiveDateTime::from_timestamp(1483228800 + (minutes * 60), 0);
+
+    let mut builder = CandleBuilder::new(1, 300);
+    for &(minutes, rate) in &[(0, 2.), (1, 3.), (4, 1.), (5, 4.), (12, 5.), (14, 6.)] {
+        builder.push(time(minutes), rate);
+    }
+    let candles = builder.finish();
+    let summary: Vec<(NaiveDateTime, f32, f32, f32, f32, i32)> = candles.iter()
+        .map(|c| (c.start_time, c.open, c.high, c.low, c.close, c.observations))
+        .collect();
+    assert_eq!(summary, vec![
+        (time(0), 2., 3., 1., 1., 3),
+        (time(5), 4., 4., 4., 4., 1),
+        (time(10), 5., 6., 5., 6., 2),
+    ]);
+
+    let merged = merge_candles(&candles, 900);
+    assert_eq!(merged, vec![Candle { date: time(0).timestamp(), open: 2., high: 6., low: 1., close: 6., volume: None }]);
+
+    assert_eq!(source_period(900), Some(300));
+    assert_eq!(source_period(14400), Some(3600));
+    assert_eq!(source_period(86400), Some(86400));
+    assert_eq!(source_period(60), None);
+    assert_eq!(source_period(0), None);
+}
@@ -0,0 +1,112 @@
+//! Persists the progress of trade history downloads so that an interrupted ingestion run can be resumed exactly where
+//! it left off rather than from the most recently stored trade.
+
+use chrono::{NaiveDateTime, Utc};
+use diesel;
+use diesel::prelude::*;
+use diesel::mysql::MysqlConnection;
+
+use super::debug;
+use prefiller::Segment;
+
+table! {
+    ingestion_checkpoints (pair) {
+        pair -> Varchar,
+        segment_start -> BigInt,
+        segment_end -> BigInt,
+        max_end -> BigInt,
+        final_end -> BigInt,
+        backtracking -> Bool,
+        complete -> Bool,
+        updated_at -> Timestamp,
+    }
+}
+
+/// The state of the download of a single pair as of the last chunk that was successfully stored
+#[derive(Clone, Debug, PartialEq, Queryable, Insertable)]
+#[table_name="ingestion_checkpoints"]
+pub struct Checkpoint {
+    pub pair: String,
+    pub segment_start: i64,
+    pub segment_end: i64,
+    pub max_end: i64,
+    pub final_end: i64,
+    pub backtracking: bool,
+    /// `true` if every segment up to `final_end` has been downloaded
+    pub complete: bool,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Checkpoint {
+    pub fn new(pair: &str, segment: &Segment, complete: bool) -> Checkpoint {
+        Checkpoint {
+            pair: String::from(pair),
+            segment_start: segment.start,
+            segment_end: segment.end,
+            max_end: segment.max_end,
+            final_end: segment.final_end,
+            backtracking: segment.backtracking,
+            complete: complete,
+            updated_at: Utc::now().naive_utc(),
+        }
+    }
+
+    /// Returns the segment that should be downloaded next in order to resume the download.
+    pub fn segment(&self) -> Segment {
+        Segment {
+            start: self.segment_start,
+            end: self.segment_end,
+            max_end: self.max_end,
+            final_end: self.final_end,
+            backtracking: self.backtracking,
+        }
+    }
+}
+
+pub fn create_checkpoints_table(conn: &MysqlConnection) -> Result<(), String> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS `ingestion_checkpoints` (
+            pair VARCHAR(32) PRIMARY KEY NOT NULL,
+            segment_start BIGINT NOT NULL,
+            segment_end BIGINT NOT NULL,
+            max_end BIGINT NOT NULL,
+            final_end BIGINT NOT NULL,
+            backtracking BOOL NOT NULL,
+            complete BOOL NOT NULL,
+            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+        );"
+    ).map_err(debug)?;
+
+    Ok(())
+}
+
+pub fn load_checkpoint(pair: &str, conn: &MysqlConnection) -> Result<Option<Checkpoint>, String> {
+    use self::ingestion_checkpoints::dsl;
+
+    let res: Vec<Checkpoint> = dsl::ingestion_checkpoints
+        .filter(dsl::pair.eq(pair))
+        .load(conn)
+        .map_err(debug)?;
+
+    Ok(res.into_iter().next())
+}
+
+/// Replaces the stored checkpoint for the checkpoint's pair with the supplied one.
+pub fn save_checkpoint(checkpoint: &Checkpoint, conn: &MysqlConnection) -> Result<(), String> {
+    use self::ingestion_checkpoints::dsl;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::delete(dsl::ingestion_checkpoints.filter(dsl::pair.eq(&checkpoint.pair))).execute(conn)?;
+        diesel::insert(checkpoint).into(dsl::ingestion_checkpoints).execute(conn)?;
+        Ok(())
+    }).map_err(debug)
+}
+
+pub fn list_checkpoints(conn: &MysqlConnection) -> Result<Vec<Checkpoint>, String> {
+    use self::ingestion_checkpoints::dsl;
+
+    dsl::ingestion_checkpoints
+        .order(dsl::pair.asc())
+        .load(conn)
+        .map_err(debug)
+}
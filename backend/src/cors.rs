@@ -1,11 +1,32 @@
 //! CORS implementation stolen from https://github.com/SergioBenitez/Rocket/issues/25#issuecomment-313895086
+//!
+//! The fairing applies the policy configured in the `cors` section of the configuration to every response.  It also
+//! answers all preflight `OPTIONS` requests itself, so the routes don't need their own `OPTIONS` handlers.
 
 use rocket::{Request, Response};
 use rocket::fairing::{Fairing, Info, Kind};
-use rocket::http::{Header, ContentType, Method};
+use rocket::http::{Header, ContentType, Method, Status};
 use std::io::Cursor;
 
-pub struct CORS();
+use config::CorsConfig;
+
+/// Adds the headers of the configured CORS policy to every response
+pub struct CORS(pub CorsConfig);
+
+impl CORS {
+    /// Returns the value of the `Access-Control-Allow-Origin` header for a request from the given origin, or `None` if
+    /// the origin isn't allowed.  When all origins are allowed, the answer is always "*" so that no origin is ever granted
+    /// access to credentials.
+    fn allowed_origin(&self, origin: Option<&str>) -> Option<String> {
+        if self.0.allowed_origins.iter().any(|allowed| allowed == "*") {
+            return Some(String::from("*"));
+        }
+        match origin {
+            Some(origin) if self.0.allowed_origins.iter().any(|allowed| allowed == origin) => Some(String::from(origin)),
+            _ => None,
+        }
+    }
+}
 
 impl Fairing for CORS {
     fn info(&self) -> Info {
@@ -16,16 +37,86 @@ impl Fairing for CORS {
     }
 
     fn on_response(&self, request: &Request, response: &mut Response) {
-        if request.method() == Method::Options || response.content_type() == Some(ContentType::JSON) {
-            response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
-            response.set_header(Header::new("Access-Control-Allow-Methods", "POST, GET, OPTIONS"));
-            response.set_header(Header::new("Access-Control-Allow-Headers", "Content-Type"));
-            response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+        match self.allowed_origin(request.headers().get_one("Origin")) {
+            Some(origin) => {
+                response.set_header(Header::new("Access-Control-Allow-Origin", origin.clone()));
+                // browsers reject credentials for "*" anyway, but it mustn't be combined with them in the first place
+                if self.0.allow_credentials && origin != "*" {
+                    response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+                }
+            },
+            None => {
+                response.remove_header("Access-Control-Allow-Origin");
+                response.remove_header("Access-Control-Allow-Credentials");
+            },
         }
+        // listed origins are echoed back, so caches mustn't share responses between origins
+        response.set_header(Header::new("Vary", "Origin"));
 
         if request.method() == Method::Options {
+            response.set_header(Header::new("Access-Control-Allow-Methods", self.0.allowed_methods.join(", ")));
+            response.set_header(Header::new("Access-Control-Allow-Headers", self.0.allowed_headers.join(", ")));
+            if self.0.max_age > 0 {
+                response.set_header(Header::new("Access-Control-Max-Age", self.0.max_age.to_string()));
+            }
+
+            // no routes handle `OPTIONS` requests, so they'd otherwise be answered with a 404
+            response.set_status(Status::Ok);
             response.set_header(ContentType::Plain);
             response.set_sized_body(Cursor::new(""));
         }
     }
 }
+
+#[test]
+fn test_cors_policy() {
+    use rocket::local::Client;
+
+    let config = CorsConfig {
+        allowed_origins: vec![String::from("https://polotrack.example")],
+        max_age: 600,
+        ..CorsConfig::default()
+    };
+    let client = Client::new(::rocket::ignite().attach(CORS(config))).unwrap();
+
+    let res = client.options("/batch_rate").header(Header::new("Origin", "https://polotrack.example")).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(res.headers().get_one("Access-Control-Allow-Origin"), Some("https://polotrack.example"));
+    assert_eq!(res.headers().get_one("Access-Control-Allow-Methods"), Some("GET, POST, OPTIONS"));
+    assert_eq!(res.headers().get_one("Access-Control-Max-Age"), Some("600"));
+
+    let res = client.options("/batch_rate").header(Header::new("Origin", "http://host.tld")).dispatch();
+    assert_eq!(res.headers().get_one("Access-Control-Allow-Origin"), None);
+    let res = client.get("/currencies").header(Header::new("Origin", "https://polotrack.example")).dispatch();
+    assert_eq!(res.headers().get_one("Access-Control-Allow-Origin"), Some("https://polotrack.example"));
+    assert_eq!(res.headers().get_one("Access-Control-Allow-Methods"), None);
+
+    let allow_all = CORS(CorsConfig::default());
+    assert_eq!(allow_all.allowed_origin(Some("http://host.tld")), Some(String::from("*")));
+    assert_eq!(allow_all.allowed_origin(None), Some(String::from("*")));
+}
+
+#[test]
+fn test_cors_unlisted_origin() {
+    use rocket::local::Client;
+
+    // credentials are only ever allowed for listed origins
+    let config = CorsConfig {
+        allowed_origins: vec![String::from("https://polotrack.example")],
+        allow_credentials: true,
+        ..CorsConfig::default()
+    };
+    let client = Client::new(::rocket::ignite().attach(CORS(config))).unwrap();
+    let res = client.get("/currencies").header(Header::new("Origin", "https://attacker.example")).dispatch();
+    assert_eq!(res.headers().get_one("Access-Control-Allow-Origin"), None);
+    assert_eq!(res.headers().get_one("Access-Control-Allow-Credentials"), None);
+    let res = client.get("/currencies").header(Header::new("Origin", "https://polotrack.example")).dispatch();
+    assert_eq!(res.headers().get_one("Access-Control-Allow-Credentials"), Some("true"));
+
+    // allowing all origins never echoes the origin or allows credentials, even if they're enabled
+    let config = CorsConfig { allow_credentials: true, ..CorsConfig::default() };
+    let client = Client::new(::rocket::ignite().attach(CORS(config))).unwrap();
+    let res = client.get("/currencies").header(Header::new("Origin", "https://attacker.example")).dispatch();
+    assert_eq!(res.headers().get_one("Access-Control-Allow-Origin"), Some("*"));
+    assert_eq!(res.headers().get_one("Access-Control-Allow-Credentials"), None);
+}
@@ -1,102 +1,527 @@
 //! Functions for interfacing with the database using Diesel
 
-use chrono::NaiveDateTime;
+use chrono::{Duration, NaiveDateTime, Utc};
 use diesel::expression::sql_literal;
 use diesel::prelude::*;
 use diesel::mysql::MysqlConnection;
+use diesel::types::Text;
 use r2d2::{ Config, Pool };
 use r2d2_diesel_mysql::ConnectionManager;
 
 // use schema;
-use secret::DB_URL;
-use super::{debug, MYSQL_DATE_FORMAT};
-
-/// Helper type holding the rate and difference from the current timestamp of a historical rate query
-#[derive(Copy, Clone, Queryable, PartialEq, Debug)]
-pub struct HistRateQueryResult(pub f32, pub i32);
-
-/// All valid currencies that are accepted by the API and may, at one point, have been in someone's Poloniex account.
-const CURRENCIES: &[&'static str] = &[
-    "1CR","ABY","AC","ACH","ADN","AEON","AERO","AIR","AMP","APH","ARCH","ARDR","AUR",
-    "AXIS","BALLS","BANK","BBL","BBR","BCC","BCN","BCY","BDC","BDG","BELA","BITCNY","BITS","BITUSD","BLK","BLOCK","BLU",
-    "BNS","BONES","BOST","BTC", "BTCD","BTCS","BTM","BTS","BURN","BURST","C2","CACH","CAI","CC","CCN","CGA","CHA","CINNI",
-    "CLAM","CNL","CNMT","CNOTE","COMM","CON","CORG","CRYPT","CURE","CYC","DAO","DASH","DCR","DGB","DICE","DIEM","DIME",
-    "DIS","DNS","DOGE","DRKC","DRM","DSH","DVK","EAC","EBT","ECC","EFL","EMC2","EMO","ENC","ETC","ETH","eTOK","EXE","EXP",
-    "FAC","FCN","FCT","FIBRE","FLAP","FLDC","FLO","FLT","FOX","FRAC","FRK","FRQ","FVZ","FZ","FZN","GAME","GAP","GDN",
-    "GEMZ","GEO","GIAR","GLB","GML","GNO","GNS","GNT","GOLD","GPC","GPUC","GRC","GRCX","GRS","GUE","H2O","HIRO","HOT","HUC",
-    "HUGE","HVC","HYP","HZ","IFC","INDEX","IOC","ITC","IXC","JLH","JPC","JUG","KDC","KEY","LBC","LC","LCL","LEAF","LGC",
-    "LOL","LOVE","LQD","LSK","LTBC","LTC","LTCX","MAID","MAST","MAX","MCN","MEC","METH","MIL","MIN","MINT","MMC","MMNXT",
-    "MMXIV","MNTA","MON","MRC","MRS","MTS","MUN","MYR","MZC","N5X","NAS","NAUT","NAV","NBT","NEOS","NL","NMC","NOBL",
-    "NOTE","NOXT","NRS","NSR","NTX","NXC","NXT","NXTI","OMNI","OPAL","PAND","PASC","PAWN","PIGGY","PINK","PLX","PMC",
-    "POT","PPC","PRC","PRT","PTS","Q2C","QBK","QCN","QORA","QTL","RADS","RBY","RDD","REP","RIC","RZR","SBD","SC","SDC",
-    "SHIBE","SHOPX","SILK","SJCX","SLR","SMC","SOC","SPA","SQL","SRCC","SRG","SSD","STEEM","STR","STRAT","SUM","SUN",
-    "SWARM","SXC","SYNC","SYS","TAC","TOR","TRUST","TWE","UIS","ULTC","UNITY","URO","USDE","USDT","UTC","UTIL","UVC",
-    "VIA","VOOT","VOX","VRC","VTC","WC","WDC","WIKI","WOLF","X13","XAI","XAP","XBC","XC","XCH","XCN","XCP","XCR","XDN",
-    "XDP","XEM","XHC","XLB","XMG","XMR","XPB","XPM","XRP","XSI","XST","XSV","XUSD","XVC","XXC","YACC","YANG","YC","YIN",
-    "ZEC", "USD", "EUR", "JPY", "GBP", "CAD", "NZD", "NOK"
-];
-
-const BASE_CURRENCIES: &[&'static str] = &["USD", "EUR", "JPY", "GBP", "CAD", "NZD", "NOK"];
-
-pub fn create_db_pool() -> Pool<ConnectionManager<MysqlConnection>> {
-    let config = Config::default();
-    let manager = ConnectionManager::<MysqlConnection>::new(DB_URL);
-    Pool::new(config, manager).expect("Failed to create pool.")
+use super::debug;
+use config::DatabaseConfig;
+use error::ApiError;
+use registry::PairRegistry;
+use store::{nearer, RateStore};
+
+/// A stored observation that was used to compute a historical rate
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct RateLeg {
+    /// The stored pair that the observation belongs to, formatted like "BTC/XMR"
+    pub pair: String,
+    pub rate: f32,
+    /// The time at which the observation was recorded
+    #[serde(with = "::timestamp::utc")]
+    pub time: NaiveDateTime,
 }
 
-/// Given a pair and a timestamp, returns the exchange rate for that pair to BTC as close as possible to the provided timestamp.
-/// Expects a pair in the format "BTC/ETH".
-pub fn get_rate(pair: &str, timestamp: NaiveDateTime, conn: &MysqlConnection) -> Result<Option<HistRateQueryResult>, String> {
-    let mut split = pair.split('/').collect::<Vec<&str>>();
+/// Helper type holding the rate, the difference in minutes from the current timestamp, and the stored observations
+/// that were used to compute the rate for a historical rate query
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct HistRateQueryResult {
+    pub rate: f32,
+    pub minutes_ago: i64,
+    pub legs: Vec<RateLeg>,
+}
+
+impl HistRateQueryResult {
+    /// Returns the number of seconds between the oldest and the newest observations used to compute the rate.
+    pub fn leg_skew_seconds(&self) -> i64 {
+        let min = self.legs.iter().map(|leg| leg.time).min();
+        let max = self.legs.iter().map(|leg| leg.time).max();
+        match (min, max) {
+            (Some(min), Some(max)) => max.signed_duration_since(min).num_seconds(),
+            _ => 0,
+        }
+    }
+
+    /// Returns the time of the observation used to compute the rate that is farthest from the supplied timestamp along
+    /// with its distance from the timestamp in seconds.  The distance is negative for observations before the timestamp.
+    pub fn farthest_observation(&self, timestamp: NaiveDateTime) -> Option<(NaiveDateTime, i64)> {
+        self.legs.iter()
+            .map(|leg| (leg.time, leg.time.signed_duration_since(timestamp).num_seconds()))
+            .max_by_key(|&(_, distance)| distance.abs())
+    }
+}
+
+/// The largest bucket size that timestamps can be quantized to
+pub const MAX_BUCKET_SECONDS: i64 = 24 * 60 * 60;
+/// The largest search radius that can be requested, which keeps the queries for averaged rates reasonably small
+pub const MAX_SEARCH_RADIUS_SECONDS: i64 = 7 * 24 * 60 * 60;
+
+/// Fiat currencies for which BTC prices are downloaded from Coinbase.
+pub const BASE_CURRENCIES: &[&'static str] = &["USD", "EUR", "JPY", "GBP", "CAD", "NZD", "NOK"];
+
+pub fn create_db_pool(config: &DatabaseConfig) -> Pool<ConnectionManager<MysqlConnection>> {
+    let pool_config = Config::builder().pool_size(config.pool_size).build();
+    let manager = ConnectionManager::<MysqlConnection>::new(config.url.as_str());
+    Pool::new(pool_config, manager).expect("Failed to create pool.")
+}
+
+/// Returns the names of all `trades_*` tables in the current database, sorted by name.
+pub fn list_trade_tables(conn: &MysqlConnection) -> Result<Vec<String>, String> {
+    sql_literal::sql::<Text>(
+        "SELECT table_name FROM information_schema.tables
+        WHERE table_schema = DATABASE() AND table_name LIKE 'trades\\_%'
+        ORDER BY table_name"
+    ).load(conn).map_err(debug)
+}
+
+/// The strategy used to compute a rate from the observations surrounding the requested timestamp
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RateMode {
+    /// The observation nearest to the requested timestamp on either side of it
+    Nearest,
+    /// The last observation at or before the requested timestamp, which never looks into the future
+    Previous,
+    /// Linear interpolation between the last observation before and the first observation after the requested timestamp
+    Interpolate,
+    /// The time-weighted average of all observations in the search window leading up to the requested timestamp.  Trade
+    /// volumes aren't stored, so each observation is weighted by the amount of time until the next one.
+    Twap,
+}
+
+impl Default for RateMode {
+    fn default() -> RateMode {
+        RateMode::Nearest
+    }
+}
+
+impl RateMode {
+    /// Parses a mode in the format that it's serialized in, for example "previous".
+    pub fn parse(mode: &str) -> Option<RateMode> {
+        match mode {
+            "nearest" => Some(RateMode::Nearest),
+            "previous" => Some(RateMode::Previous),
+            "interpolate" => Some(RateMode::Interpolate),
+            "twap" => Some(RateMode::Twap),
+            _ => None,
+        }
+    }
+}
+
+/// Options controlling how a rate is resolved from the stored observations
+#[derive(Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
+pub struct RateOptions {
+    pub mode: RateMode,
+    /// The maximum number of seconds between the requested timestamp and the observations used to compute the rate.  If
+    /// not supplied, 13 hours are searched for fiat pairs and 4 hours for all other pairs.
+    pub max_distance: Option<i64>,
+    /// If set, requested timestamps are rounded down to a multiple of this many seconds before the rate is resolved so
+    /// that nearby requests share the same cached rate.
+    pub bucket: Option<i64>,
+}
+
+impl RateOptions {
+    /// Checks that a requested maximum distance in seconds is positive and no larger than `MAX_SEARCH_RADIUS_SECONDS`.
+    pub fn validate_max_distance(max_distance: i64) -> Result<i64, String> {
+        if max_distance <= 0 || max_distance > MAX_SEARCH_RADIUS_SECONDS {
+            return Err(format!(
+                "The maximum distance must be between 1 and {} seconds; got {}.", MAX_SEARCH_RADIUS_SECONDS, max_distance
+            ));
+        }
+
+        Ok(max_distance)
+    }
+
+    /// Checks that a requested bucket size in seconds is positive and no larger than `MAX_BUCKET_SECONDS`.
+    pub fn validate_bucket(bucket: i64) -> Result<i64, String> {
+        if bucket <= 0 || bucket > MAX_BUCKET_SECONDS {
+            return Err(format!("The bucket size must be between 1 and {} seconds; got {}.", MAX_BUCKET_SECONDS, bucket));
+        }
+
+        Ok(bucket)
+    }
+
+    /// Returns the start of the bucket containing the supplied timestamp, or the timestamp itself if timestamps aren't
+    /// bucketed.
+    pub fn bucket_timestamp(&self, timestamp: NaiveDateTime) -> NaiveDateTime {
+        match self.bucket {
+            Some(bucket) => {
+                let seconds = timestamp.timestamp();
+                NaiveDateTime::from_timestamp(seconds - (((seconds % bucket) + bucket) % bucket), 0)
+            },
+            None => timestamp,
+        }
+    }
+}
+
+/// Linearly interpolates the rate at the supplied timestamp, which must lie between the two observations.
+fn interpolate(timestamp: NaiveDateTime, before: (NaiveDateTime, f32), after: (NaiveDateTime, f32)) -> f32 {
+    let span = after.0.signed_duration_since(before.0).num_milliseconds();
+    if span <= 0 {
+        return before.1;
+    }
+    let elapsed = timestamp.signed_duration_since(before.0).num_milliseconds();
+    let fraction = elapsed as f64 / span as f64;
+
+    (before.1 as f64 + ((after.1 as f64 - before.1 as f64) * fraction)) as f32
+}
+
+/// Computes the time-weighted average of the supplied observations, which must be sorted from oldest to newest.  Each
+/// observation is weighted by the amount of time until the next one, with the last one lasting until `end`.
+fn time_weighted_average(observations: &[(NaiveDateTime, f32)], end: NaiveDateTime) -> Option<f32> {
+    let mut weighted_sum = 0f64;
+    let mut total_weight = 0i64;
+    for (i, &(time, rate)) in observations.iter().enumerate() {
+        let next = observations.get(i + 1).map(|&(next, _)| next).unwrap_or(end);
+        let weight = next.signed_duration_since(time).num_milliseconds();
+        weighted_sum += rate as f64 * weight as f64;
+        total_weight += weight;
+    }
+
+    match observations.last() {
+        // all observations were made at the requested timestamp
+        Some(&(_, rate)) if total_weight == 0 => Some(rate),
+        Some(_) => Some((weighted_sum / total_weight as f64) as f32),
+        None => None,
+    }
+}
+
+/// Resolves the rate of the given pair at the supplied timestamp using observations within the search radius.  Returns
+/// the computed rate along with the time of the observation nearest to the timestamp that it was computed from.  The
+/// observations are read from the supplied rate store.
+fn resolve_observation(
+    pair_id: i32, timestamp: NaiveDateTime, search_radius: Duration, mode: RateMode, store: &RateStore
+) -> Result<Option<(NaiveDateTime, f32)>, ApiError> {
+    let (search_start, search_end) = match (
+        timestamp.checked_sub_signed(search_radius), timestamp.checked_add_signed(search_radius)
+    ) {
+        (Some(start), Some(end)) => (start, end),
+        _ => { return Err(ApiError::InvalidRequest(format!("Timestamp out of range: {}", timestamp))); },
+    };
+
+    resolve_in_window(pair_id, timestamp, search_start, search_end, mode, store).map_err(ApiError::Backend)
+}
+
+/// Resolves the rate of the given pair at the supplied timestamp from the observations between `search_start` and
+/// `search_end`.
+fn resolve_in_window(
+    pair_id: i32, timestamp: NaiveDateTime, search_start: NaiveDateTime, search_end: NaiveDateTime, mode: RateMode,
+    store: &RateStore
+) -> Result<Option<(NaiveDateTime, f32)>, String> {
+    match mode {
+        RateMode::Previous => store.before(pair_id, timestamp, search_start),
+        RateMode::Twap => {
+            let observations = store.range(pair_id, search_start, timestamp)?;
+            let time = observations.last().map(|&(time, _)| time);
+            Ok(time.and_then(|time| time_weighted_average(&observations, timestamp).map(|rate| (time, rate))))
+        },
+        RateMode::Nearest => store.nearest(pair_id, timestamp, search_start, search_end),
+        RateMode::Interpolate => {
+            let before = store.before(pair_id, timestamp, search_start)?;
+            let after = store.after(pair_id, timestamp, search_end)?;
+
+            Ok(match (before, after) {
+                (Some(before), Some(after)) => {
+                    let (time, _) = nearer(timestamp, before, after);
+                    Some((time, interpolate(timestamp, before, after)))
+                },
+                // interpolation needs observations on both sides, so fall back to whichever one exists
+                (before, None) => before,
+                (None, after) => after,
+            })
+        },
+    }
+}
+
+/// Returns the largest distance from the requested timestamp at which observations of a stored pair with the given
+/// quote currency are used to resolve a rate.
+pub fn search_radius(quote: &str, options: RateOptions) -> Duration {
+    // base currencies have min precision of 1 obs every 24 hours; much more precise for Poloniex trade data
+    match options.max_distance {
+        Some(max_distance) => Duration::seconds(max_distance),
+        None => Duration::hours(if BASE_CURRENCIES.contains(&quote) { 13 } else { 4 }),
+    }
+}
+
+/// Splits a requested pair like "BTC/ETH" into its base and quote currencies.
+fn split_requested_pair(pair: &str) -> Result<(&str, &str), ApiError> {
+    let split = pair.split('/').collect::<Vec<&str>>();
     if split.len() < 2 {
-        return Err(format!("Invalid currency pair supplied: {}!", pair))
+        return Err(ApiError::InvalidPair(String::from(pair)))
+    }
+
+    Ok((split[0], split[1]))
+}
+
+/// Returns the base and quote currencies of the stored pair that holds the rate between BTC and the given currency.
+/// Poloniex lists USDT as "USDT_BTC", so unlike every other currency it's stored with BTC as its quote currency.
+fn btc_pair(currency: &str) -> (&str, &str) {
+    if currency == "USDT" { ("USDT", "BTC") } else { ("BTC", currency) }
+}
+
+/// Returns `true` if the stored rate between BTC and the given currency is the value of one BTC in that currency.
+fn priced_in_currency(currency: &str) -> bool {
+    currency == "USDT" || BASE_CURRENCIES.contains(&currency)
+}
+
+/// Returns the stored pair that holds the rate of the requested pair without any conversion, if there is one.
+/// "BTC/USDT" is resolved from the stored "USDT/BTC" pair, which holds the value of one BTC in USDT.
+fn direct_pair<'a>(base: &'a str, quote: &'a str, registry: &PairRegistry) -> Option<(&'a str, &'a str)> {
+    let stored = if base == "BTC" { btc_pair(quote) } else { (base, quote) };
+    registry.lookup(stored.0, stored.1).map(|_| stored)
+}
+
+/// Returns the IDs of the stored pairs whose observations are used to compute the rate of the given pair along with
+/// their quote currencies.
+pub fn stored_legs<'a>(pair: &'a str, registry: &PairRegistry) -> Result<Vec<(i32, &'a str)>, ApiError> {
+    let (base, quote) = split_requested_pair(pair)?;
+    if base == "BTC" && quote == "BTC" {
+        return Ok(Vec::new());
+    }
+    if let Some((stored_base, stored_quote)) = direct_pair(base, quote, registry) {
+        return Ok(vec![(registry.lookup(stored_base, stored_quote).unwrap(), stored_quote)]);
     }
 
-    if split[0] == "BTC" && split[1] == "BTC" {
-        Ok(Some(HistRateQueryResult(1f32, 1000000)))
-    } else if CURRENCIES.contains(&split[0]) && CURRENCIES.contains(&split[1]) {
-        if split[0] == "USDT" || split[1] == "USDT" {
-            split[0] = "BTC";
-            split[1] = "USDT";
+    let mut legs = Vec::new();
+    for currency in &[base, quote] {
+        if *currency == "BTC" {
+            continue;
         }
-        // base currencies have min precision of 1 obs every 24 hours; much more precise for Poloniex trade data
-        let search_radius = if BASE_CURRENCIES.contains(&split[1]) { 13 } else { 4 };
-        // have to construct raw SQL here since Diesel doesn't deal well with dynamic queries and writing macros is horrible
-        let formatted_timestamp = timestamp.format(MYSQL_DATE_FORMAT);
-        // create a query to find the trade nearest to the supplied timestamp within one day on either side.  Will return no rows if there
-        // were no trades in the requested pair on one day on either side of the supplied timestamp.
-        let query = format!(
-            "SELECT `rate`, TIMESTAMPDIFF(MINUTE, '{}', CURRENT_TIMESTAMP)
-            FROM `trades_{}_{}`
-            WHERE `trade_time` BETWEEN DATE_SUB('{}', INTERVAL {} HOUR)
-              AND DATE_ADD('{}', INTERVAL {} HOUR)
-            ORDER BY abs(TIMESTAMPDIFF(SECOND, '{}', `trade_time`))
-            LIMIT 1",
-            formatted_timestamp, split[0], split[1], formatted_timestamp,
-            search_radius, formatted_timestamp, search_radius, formatted_timestamp
-        );
-        let select_statement = sql_literal::sql(&query);
-        let res: Vec<HistRateQueryResult> = select_statement
-            .load::<HistRateQueryResult>(conn)
-            .map_err(debug)?;
-        if res.len() > 0 {
-            Ok(Some(res[0]))
-        } else {
-            Ok(None)
+        let (leg_base, leg_quote) = btc_pair(*currency);
+        match registry.lookup(leg_base, leg_quote) {
+            Some(pair_id) => legs.push((pair_id, leg_quote)),
+            None => { return Err(ApiError::InvalidPair(String::from(pair))); },
         }
-    } else {
-        println!("Requested currency pair {} but we don't have data for that.", pair);
-        Err(String::from("Invalid currency pair supplied."))
     }
+
+    Ok(legs)
+}
+
+/// Resolves the rate of the stored pair with the given base and quote currencies at the timestamp.
+fn get_leg(
+    base: &str, quote: &str, timestamp: NaiveDateTime, options: RateOptions, registry: &PairRegistry, store: &RateStore
+) -> Result<Option<RateLeg>, ApiError> {
+    let pair_id = match registry.lookup(base, quote) {
+        Some(pair_id) => pair_id,
+        None => {
+            println!("Requested currency pair {}/{} but we don't have data for that.", base, quote);
+            return Err(ApiError::InvalidPair(format!("{}/{}", base, quote)));
+        },
+    };
+
+    let observation = resolve_observation(pair_id, timestamp, search_radius(quote, options), options.mode, store)?;
+    Ok(observation.map(|(time, rate)| RateLeg { pair: format!("{}/{}", base, quote), rate: rate, time: time }))
+}
+
+/// Reports errors about a stored pair that's missing as errors about the requested pair, which is what clients know about.
+fn for_requested_pair(pair: &str, err: ApiError) -> ApiError {
+    match err {
+        ApiError::InvalidPair(_) => ApiError::InvalidPair(String::from(pair)),
+        err => err,
+    }
+}
+
+/// Returns the value of one unit of the given currency in BTC along with the observation used to compute it.  Coins are
+/// stored as "BTC/<coin>" pairs holding the value of the coin in BTC while fiat currencies are stored as "BTC/<fiat>"
+/// pairs and USDT as the "USDT/BTC" pair, both holding the value of one BTC in that currency.
+fn btc_value(
+    currency: &str, timestamp: NaiveDateTime, options: RateOptions, registry: &PairRegistry, store: &RateStore
+) -> Result<Option<(f64, Option<RateLeg>)>, ApiError> {
+    if currency == "BTC" {
+        return Ok(Some((1.0, None)));
+    }
+
+    let (leg_base, leg_quote) = btc_pair(currency);
+    let leg = match get_leg(leg_base, leg_quote, timestamp, options, registry, store)? {
+        Some(ref leg) if leg.rate > 0. => leg.clone(),
+        _ => { return Ok(None); },
+    };
+    let value = if priced_in_currency(currency) { 1. / leg.rate as f64 } else { leg.rate as f64 };
+    Ok(Some((value, Some(leg))))
+}
+
+/// Given a pair and a timestamp, returns the exchange rate for that pair to BTC as close as possible to the provided timestamp.
+/// Expects a pair in the format "BTC/ETH".  The pair is resolved through the registry, and all values are bound as query
+/// parameters.
+///
+/// Pairs that aren't stored directly are computed by chaining the rates of both currencies to BTC.  Pairs with a fiat or USDT
+/// quote currency are priced like "BTC/USD", as the value of one unit of the base currency in the quote currency.  All other
+/// pairs are priced like "BTC/XMR", as the value of one unit of the quote currency in the base currency.
+///
+/// Each stored rate is resolved from the observations around the timestamp using the mode and search radius in `options`.
+pub fn get_rate(
+    pair: &str, timestamp: NaiveDateTime, options: RateOptions, registry: &PairRegistry, store: &RateStore
+) -> Result<Option<HistRateQueryResult>, ApiError> {
+    let (base, quote) = split_requested_pair(pair)?;
+    let minutes_ago = Utc::now().naive_utc().signed_duration_since(timestamp).num_minutes();
+
+    if base == "BTC" && quote == "BTC" {
+        return Ok(Some(HistRateQueryResult { rate: 1f32, minutes_ago: 1000000, legs: Vec::new() }));
+    }
+
+    // pairs that are stored directly don't need any conversion
+    if let Some((stored_base, stored_quote)) = direct_pair(base, quote, registry) {
+        let leg = get_leg(stored_base, stored_quote, timestamp, options, registry, store)
+            .map_err(|err| for_requested_pair(pair, err))?;
+        return Ok(leg.map(|leg| HistRateQueryResult { rate: leg.rate, minutes_ago: minutes_ago, legs: vec![leg] }));
+    }
+
+    let (base_value, quote_value) = match (
+        btc_value(base, timestamp, options, registry, store).map_err(|err| for_requested_pair(pair, err))?,
+        btc_value(quote, timestamp, options, registry, store).map_err(|err| for_requested_pair(pair, err))?
+    ) {
+        (Some(base_value), Some(quote_value)) => (base_value, quote_value),
+        _ => { return Ok(None); },
+    };
+    let rate = if priced_in_currency(quote) {
+        base_value.0 / quote_value.0
+    } else {
+        quote_value.0 / base_value.0
+    };
+    let legs = vec![base_value.1, quote_value.1].into_iter().filter_map(|leg| leg).collect();
+
+    Ok(Some(HistRateQueryResult { rate: rate as f32, minutes_ago: minutes_ago, legs: legs }))
 }
 
 #[test]
 fn test_hist_rate_retrieval() {
-    use super::DbPool;
+    use super::{DbPool, MYSQL_DATE_FORMAT};
+    use config::Config;
 
-    let pool = DbPool(create_db_pool());
+    let pool = DbPool(create_db_pool(&Config::load().unwrap().database));
+    let registry = PairRegistry::load(&pool).unwrap();
     assert_eq!(
-        get_rate("BTC/DOGE", NaiveDateTime::parse_from_str("2014-01-25 05:44:38", MYSQL_DATE_FORMAT).unwrap(), &*pool.get_conn()).unwrap().unwrap().0,
+        get_rate("BTC/DOGE", NaiveDateTime::parse_from_str("2014-01-25 05:44:38", MYSQL_DATE_FORMAT).unwrap(), RateOptions::default(), &registry, &pool).unwrap().unwrap().rate,
         0.0000015
     );
 }
+
+#[test]
+fn test_triangulated_rate_retrieval() {
+    use super::{DbPool, MYSQL_DATE_FORMAT};
+    use config::Config;
+
+    let pool = DbPool(create_db_pool(&Config::load().unwrap().database));
+    let registry = PairRegistry::load(&pool).unwrap();
+    let timestamp = NaiveDateTime::parse_from_str("2017-01-01 00:00:00", MYSQL_DATE_FORMAT).unwrap();
+
+    let btc_eth = get_rate("BTC/ETH", timestamp, RateOptions::default(), &registry, &pool).unwrap().unwrap();
+    let btc_xmr = get_rate("BTC/XMR", timestamp, RateOptions::default(), &registry, &pool).unwrap().unwrap();
+    let eth_xmr = get_rate("ETH/XMR", timestamp, RateOptions::default(), &registry, &pool).unwrap().unwrap();
+    assert_eq!(eth_xmr.rate, (btc_xmr.rate as f64 / btc_eth.rate as f64) as f32);
+    assert_eq!(eth_xmr.legs, vec![btc_eth.legs[0].clone(), btc_xmr.legs[0].clone()]);
+
+    let btc_usd = get_rate("BTC/USD", timestamp, RateOptions::default(), &registry, &pool).unwrap().unwrap();
+    let xmr_usd = get_rate("XMR/USD", timestamp, RateOptions::default(), &registry, &pool).unwrap().unwrap();
+    assert_eq!(xmr_usd.rate, (btc_xmr.rate as f64 * btc_usd.rate as f64) as f32);
+    assert_eq!(xmr_usd.legs.len(), 2);
+}
+
+#[test]
+fn test_in_memory_rate_retrieval() {
+    use index::RateIndex;
+    use normalized::PairInfo;
+    use registry::PairMetadata;
+
+    let time = |minutes: i64| NaiveDateTime::from_timestamp(1483228800 + (minutes * 60), 0);
+    let pair = |id: i32, quote: &str| {
+        PairMetadata::new(&PairInfo { id: id, base: String::from("BTC"), quote: String::from(quote) }, None)
+    };
+    let store = RateIndex::from_observations(vec![
+        (pair(1, "XMR"), vec![(time(0), 0.25), (time(10), 0.5)]),
+        (pair(2, "USD"), vec![(time(5), 1000.)]),
+    ]);
+    let registry = PairRegistry::load(&store).unwrap();
+
+    let btc_xmr = get_rate("BTC/XMR", time(4), RateOptions::default(), &registry, &store).unwrap().unwrap();
+    assert_eq!(btc_xmr.rate, 0.25);
+    let options = RateOptions { mode: RateMode::Interpolate, ..RateOptions::default() };
+    let btc_xmr = get_rate("BTC/XMR", time(5), options, &registry, &store).unwrap().unwrap();
+    assert_eq!(btc_xmr.rate, 0.375);
+    assert_eq!(btc_xmr.legs[0].time, time(0));
+
+    let xmr_usd = get_rate("XMR/USD", time(10), RateOptions::default(), &registry, &store).unwrap().unwrap();
+    assert_eq!(xmr_usd.rate, 500.);
+    assert_eq!(xmr_usd.legs.len(), 2);
+    let err = get_rate("ETH/USD", time(10), RateOptions::default(), &registry, &store).unwrap_err();
+    assert_eq!(err, ApiError::InvalidPair(String::from("ETH/USD")));
+
+    assert_eq!(stored_legs("XMR/USD", &registry), Ok(vec![(1, "XMR"), (2, "USD")]));
+    assert_eq!(stored_legs("BTC/XMR", &registry), Ok(vec![(1, "XMR")]));
+    assert_eq!(stored_legs("ETH/USD", &registry), Err(ApiError::InvalidPair(String::from("ETH/USD"))));
+}
+
+#[test]
+fn test_usdt_rate_retrieval() {
+    use index::RateIndex;
+    use normalized::PairInfo;
+    use registry::PairMetadata;
+
+    let time = |minutes: i64| NaiveDateTime::from_timestamp(1483228800 + (minutes * 60), 0);
+    let pair = |id: i32, base: &str, quote: &str| {
+        PairMetadata::new(&PairInfo { id: id, base: String::from(base), quote: String::from(quote) }, None)
+    };
+    let store = RateIndex::from_observations(vec![
+        (pair(1, "BTC", "XMR"), vec![(time(0), 0.25)]),
+        (pair(3, "USDT", "BTC"), vec![(time(0), 1000.)]),
+    ]);
+    let registry = PairRegistry::load(&store).unwrap();
+
+    // USDT is stored as "USDT/BTC", which holds the value of one BTC in USDT
+    let btc_usdt = get_rate("BTC/USDT", time(0), RateOptions::default(), &registry, &store).unwrap().unwrap();
+    assert_eq!(btc_usdt.rate, 1000.);
+    assert_eq!(btc_usdt.legs[0].pair, "USDT/BTC");
+    let xmr_usdt = get_rate("XMR/USDT", time(0), RateOptions::default(), &registry, &store).unwrap().unwrap();
+    assert_eq!(xmr_usdt.rate, 250.);
+    assert_eq!(xmr_usdt.legs.len(), 2);
+
+    assert_eq!(stored_legs("BTC/USDT", &registry), Ok(vec![(3, "BTC")]));
+    assert_eq!(stored_legs("XMR/USDT", &registry), Ok(vec![(1, "XMR"), (3, "BTC")]));
+}
+
+#[test]
+fn test_rate_resolution() {
+    let time = |minutes: i64| NaiveDateTime::from_timestamp(1483228800 + (minutes * 60), 0);
+
+    assert_eq!(interpolate(time(15), (time(10), 2.), (time(20), 4.)), 3.);
+    assert_eq!(interpolate(time(10), (time(10), 2.), (time(10), 4.)), 2.);
+    assert_eq!(nearer(time(14), (time(10), 2.), (time(20), 4.)), (time(10), 2.));
+    assert_eq!(nearer(time(16), (time(10), 2.), (time(20), 4.)), (time(20), 4.));
+
+    // 1.0 for 10 minutes, 4.0 for 20 minutes, 2.0 for 30 minutes
+    let observations = [(time(0), 1.), (time(10), 4.), (time(30), 2.)];
+    assert_eq!(time_weighted_average(&observations, time(60)), Some(2.5));
+    assert_eq!(time_weighted_average(&[(time(60), 3.)], time(60)), Some(3.));
+    assert_eq!(time_weighted_average(&[], time(60)), None);
+}
+
+#[test]
+fn test_farthest_observation() {
+    let time = |minutes: i64| NaiveDateTime::from_timestamp(1483228800 + (minutes * 60), 0);
+    let leg = |minutes: i64| RateLeg { pair: String::from("BTC/XMR"), rate: 1., time: time(minutes) };
+
+    let result = HistRateQueryResult { rate: 1., minutes_ago: 0, legs: vec![leg(-5), leg(20)] };
+    assert_eq!(result.farthest_observation(time(0)), Some((time(20), 20 * 60)));
+    assert_eq!(result.farthest_observation(time(10)), Some((time(-5), -15 * 60)));
+    assert_eq!(HistRateQueryResult { rate: 1., minutes_ago: 0, legs: Vec::new() }.farthest_observation(time(0)), None);
+
+    assert!(RateOptions::validate_max_distance(0).is_err());
+    assert!(RateOptions::validate_max_distance(MAX_SEARCH_RADIUS_SECONDS + 1).is_err());
+    assert_eq!(RateOptions::validate_max_distance(3600), Ok(3600));
+}
+
+#[test]
+fn test_timestamp_bucketing() {
+    let time = |minutes: i64| NaiveDateTime::from_timestamp(1483228800 + (minutes * 60), 0);
+
+    let options = RateOptions { bucket: Some(60), ..RateOptions::default() };
+    assert_eq!(options.bucket_timestamp(time(1) + Duration::seconds(59)), time(1));
+    assert_eq!(options.bucket_timestamp(time(-1) + Duration::seconds(1)), time(-1));
+    assert_eq!(RateOptions::default().bucket_timestamp(time(1) + Duration::seconds(59)), time(1) + Duration::seconds(59));
+    assert!(RateOptions::validate_bucket(0).is_err());
+    assert!(RateOptions::validate_bucket(MAX_BUCKET_SECONDS + 1).is_err());
+}
@@ -0,0 +1,123 @@
+//! Errors reported by the API.  Every error is sent to clients as a JSON envelope like
+//! `{"error": {"code": "invalid_pair", "message": "..."}}` with a matching HTTP status, and requests that fail before
+//! reaching a route are answered with the same envelope by the catchers defined here.
+
+use rocket::Request;
+use rocket::http::Status;
+use rocket::response::{self, status, Responder, Response};
+use rocket_contrib::Json;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ApiError {
+    /// The request is malformed, for example because a timestamp couldn't be parsed or an option is out of range
+    InvalidRequest(String),
+    /// The requested pair isn't stored and can't be computed from the stored pairs
+    InvalidPair(String),
+    /// No observations were found within the search radius of the requested timestamp
+    NoData,
+    /// The requested resource doesn't exist
+    NotFound(String),
+    /// The request to an administrative endpoint didn't supply the configured token
+    Unauthorized,
+    /// The request body is larger than the configured limit
+    PayloadTooLarge(String),
+    /// More rates were requested at once than the supplied limit allows
+    BatchTooLarge(usize),
+    /// The requested feature isn't available with the current configuration
+    Unavailable(String),
+    /// The request couldn't be answered because the rate store or another backend failed
+    Backend(String),
+}
+
+/// The body of an error as it is sent to clients
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ErrorBody {
+    /// A stable, machine-readable identifier of the kind of error, like "invalid_pair"
+    pub code: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ErrorEnvelope {
+    pub error: ErrorBody,
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match *self {
+            ApiError::InvalidRequest(_) => "invalid_request",
+            ApiError::InvalidPair(_) => "invalid_pair",
+            ApiError::NoData => "no_data",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::BatchTooLarge(_) => "batch_too_large",
+            ApiError::Unavailable(_) => "unavailable",
+            ApiError::Backend(_) => "backend_failure",
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match *self {
+            ApiError::InvalidRequest(_) => Status::BadRequest,
+            ApiError::InvalidPair(_) | ApiError::NoData | ApiError::NotFound(_) => Status::NotFound,
+            ApiError::Unauthorized => Status::Unauthorized,
+            ApiError::PayloadTooLarge(_) => Status::PayloadTooLarge,
+            ApiError::BatchTooLarge(_) => Status::UnprocessableEntity,
+            ApiError::Unavailable(_) => Status::NotImplemented,
+            ApiError::Backend(_) => Status::InternalServerError,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match *self {
+            ApiError::InvalidRequest(ref message) | ApiError::NotFound(ref message) |
+                ApiError::PayloadTooLarge(ref message) | ApiError::Unavailable(ref message) => message.clone(),
+            ApiError::BatchTooLarge(max_size) => format!("At most {} rates can be requested at once.", max_size),
+            ApiError::InvalidPair(ref pair) => format!("Rates of the pair {:?} aren't available.", pair),
+            ApiError::NoData => String::from("No observations were found within the search radius of the timestamp."),
+            ApiError::Unauthorized => String::from("A valid admin token must be supplied in the `Authorization` header."),
+            // the details of backend failures are logged rather than sent to clients
+            ApiError::Backend(_) => String::from("The rate store failed to answer the request."),
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        ErrorBody { code: String::from(self.code()), message: self.message() }
+    }
+}
+
+impl<'r> Responder<'r> for ApiError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let status = self.status();
+        Response::build_from(Json(ErrorEnvelope { error: self.body() }).respond_to(request)?)
+            .status(status)
+            .ok()
+    }
+}
+
+#[catch(400)]
+pub fn bad_request(_: &Request) -> ApiError {
+    ApiError::InvalidRequest(String::from("The request is malformed or contains invalid parameters."))
+}
+
+#[catch(404)]
+pub fn not_found(request: &Request) -> ApiError {
+    ApiError::NotFound(format!("No endpoint exists at {}.", request.uri()))
+}
+
+#[catch(413)]
+pub fn payload_too_large(_: &Request) -> ApiError {
+    ApiError::PayloadTooLarge(String::from("The request body is too large."))
+}
+
+#[catch(422)]
+pub fn unprocessable_entity(_: &Request) -> status::Custom<ApiError> {
+    let error = ApiError::InvalidRequest(String::from("The request body couldn't be parsed."));
+    status::Custom(Status::UnprocessableEntity, error)
+}
+
+#[catch(500)]
+pub fn internal_error(_: &Request) -> ApiError {
+    ApiError::Backend(String::from("Internal server error"))
+}
@@ -9,7 +9,7 @@ use hyper_native_tls::NativeTlsClient;
 use serde_json;
 
 use super::debug;
-use secret::{FEEDBACK_URL, FEEDBACK_PASSWORD};
+use config::FeedbackConfig;
 
 #[derive(Deserialize)]
 #[allow(dead_code)]
@@ -30,7 +30,7 @@ struct Feedback<'a> {
 /// Creates and sends a HTTPS request to AmeoTrack with the content of the feedback.  Once recieved there, it will trigger
 /// an email to be sent to me with the contents of the feedback message.  The response is attempted to be parsed into
 /// the expected format and the result passed back as `{success: bool}`.
-pub fn deliver_feedback(email: &str, message: &str) -> Result<(), String> {
+pub fn deliver_feedback(config: &FeedbackConfig, email: &str, message: &str) -> Result<(), String> {
     let ssl = NativeTlsClient::new().unwrap();
     let connector = HttpsConnector::new(ssl);
     let client = Client::with_connector(connector);
@@ -40,12 +40,12 @@ pub fn deliver_feedback(email: &str, message: &str) -> Result<(), String> {
         appName: "PoloTrack",
         email: email,
         message: message,
-        password: FEEDBACK_PASSWORD,
+        password: &config.password,
     };
     let mut headers = Headers::new();
     headers.set(ContentType::json());
     let body = serde_json::to_string(&feedback).unwrap();
-    let mut res = client.post(FEEDBACK_URL)
+    let mut res = client.post(&config.url)
         .headers(headers)
         .body(&body)
         .send()
@@ -0,0 +1,287 @@
+//! Scans the stored rates of every pair for stretches of time without any observations and maintains a queue of windows
+//! that should be downloaded again to fill them in.
+
+use chrono::{Duration, NaiveDateTime};
+use diesel;
+use diesel::prelude::*;
+use diesel::mysql::MysqlConnection;
+
+use super::debug;
+use db_query::BASE_CURRENCIES;
+use normalized::{self, PairInfo};
+
+/// `get_rate` searches 4 hours on either side of the requested timestamp, so gaps longer than twice that contain timestamps
+/// for which no rate can be found.
+pub const DEFAULT_GAP_THRESHOLD_HOURS: i64 = 8;
+/// Number of observations loaded from the database at a time while scanning a table
+const SCAN_PAGE_SIZE: usize = 100000;
+
+table! {
+    backfill_queue (id) {
+        id -> Integer,
+        pair -> Varchar,
+        gap_start -> Timestamp,
+        gap_end -> Timestamp,
+        completed -> Bool,
+        trades_found -> Nullable<Integer>,
+    }
+}
+
+/// A stretch of time between two consecutive observations of a pair that is longer than the scan threshold
+#[derive(Clone, Debug, PartialEq)]
+pub struct Gap {
+    pub pair: String,
+    /// The time of the last observation before the gap
+    pub start: NaiveDateTime,
+    /// The time of the first observation after the gap
+    pub end: NaiveDateTime,
+}
+
+impl Gap {
+    pub fn duration(&self) -> Duration {
+        self.end.signed_duration_since(self.start)
+    }
+}
+
+/// A gap that has been queued to be downloaded again
+#[derive(Clone, Debug, Queryable)]
+pub struct BackfillRequest {
+    pub id: i32,
+    pub pair: String,
+    pub gap_start: NaiveDateTime,
+    pub gap_end: NaiveDateTime,
+    pub completed: bool,
+    /// The number of trades that were stored when the window was downloaded again.  If this is zero, the gap is most
+    /// likely caused by the market being illiquid rather than by missing data.
+    pub trades_found: Option<i32>,
+}
+
+#[derive(Insertable)]
+#[table_name="backfill_queue"]
+struct NewBackfillRequest<'a> {
+    pair: &'a str,
+    gap_start: NaiveDateTime,
+    gap_end: NaiveDateTime,
+    completed: bool,
+}
+
+/// Finds gaps in a series of observation times that are supplied in ascending order.
+pub struct GapFinder {
+    pair: String,
+    threshold: Duration,
+    last: Option<NaiveDateTime>,
+    pub gaps: Vec<Gap>,
+}
+
+impl GapFinder {
+    pub fn new(pair: &str, threshold: Duration) -> GapFinder {
+        GapFinder {
+            pair: String::from(pair),
+            threshold: threshold,
+            last: None,
+            gaps: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, time: NaiveDateTime) {
+        if let Some(last) = self.last {
+            if time.signed_duration_since(last) > self.threshold {
+                self.gaps.push(Gap { pair: self.pair.clone(), start: last, end: time });
+            }
+        }
+        self.last = Some(time);
+    }
+}
+
+/// Returns all gaps longer than `threshold` in the stored observations of the given pair.
+pub fn scan_pair(pair: &PairInfo, threshold: Duration, conn: &MysqlConnection) -> Result<Vec<Gap>, String> {
+    use normalized::rates::dsl;
+
+    let mut finder = GapFinder::new(&pair.name(), threshold);
+    let mut last = NaiveDateTime::from_timestamp(0, 0);
+
+    // page through the observations rather than loading all of them at once
+    loop {
+        let times: Vec<NaiveDateTime> = dsl::rates
+            .filter(dsl::pair_id.eq(pair.id))
+            .filter(dsl::trade_time.gt(last))
+            .order(dsl::trade_time.asc())
+            .select(dsl::trade_time)
+            .limit(SCAN_PAGE_SIZE as i64)
+            .load(conn)
+            .map_err(debug)?;
+
+        let page_len = times.len();
+        if let Some(&time) = times.last() {
+            last = time;
+        }
+        for time in times {
+            finder.push(time);
+        }
+
+        if page_len < SCAN_PAGE_SIZE {
+            break;
+        }
+    }
+
+    Ok(finder.gaps)
+}
+
+/// Scans the observations of every registered pair for gaps longer than `threshold`.
+pub fn scan_all(threshold: Duration, conn: &MysqlConnection) -> Result<Vec<Gap>, String> {
+    let mut gaps = Vec::new();
+    for pair in normalized::list_pairs(conn)? {
+        gaps.extend(scan_pair(&pair, threshold, conn)?);
+    }
+
+    Ok(gaps)
+}
+
+/// Returns `true` if the pair's data comes from Coinbase rather than Poloniex, meaning that it can't be backfilled.
+pub fn is_fiat_pair(pair: &str) -> bool {
+    BASE_CURRENCIES.iter().any(|currency| pair == format!("BTC_{}", currency))
+}
+
+pub fn create_backfill_queue_table(conn: &MysqlConnection) -> Result<(), String> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS `backfill_queue` (
+            id INT PRIMARY KEY AUTO_INCREMENT NOT NULL,
+            pair VARCHAR(32) NOT NULL,
+            gap_start TIMESTAMP NOT NULL,
+            gap_end TIMESTAMP NOT NULL,
+            completed BOOL NOT NULL,
+            trades_found INT
+        );"
+    ).map_err(debug)?;
+
+    Ok(())
+}
+
+/// Queues the supplied gap to be downloaded again unless it's already queued.  Returns `true` if it was queued.
+pub fn queue_backfill(gap: &Gap, conn: &MysqlConnection) -> Result<bool, String> {
+    use self::backfill_queue::dsl;
+
+    let existing: Vec<BackfillRequest> = dsl::backfill_queue
+        .filter(dsl::pair.eq(&gap.pair))
+        .filter(dsl::gap_start.eq(gap.start))
+        .filter(dsl::gap_end.eq(gap.end))
+        .filter(dsl::completed.eq(false))
+        .load(conn)
+        .map_err(debug)?;
+    if !existing.is_empty() {
+        return Ok(false);
+    }
+
+    let request = NewBackfillRequest {
+        pair: &gap.pair,
+        gap_start: gap.start,
+        gap_end: gap.end,
+        completed: false,
+    };
+    diesel::insert(&request).into(dsl::backfill_queue).execute(conn).map_err(debug)?;
+    Ok(true)
+}
+
+/// Returns all queued gaps that haven't been downloaded yet, oldest first.
+pub fn pending_backfills(conn: &MysqlConnection) -> Result<Vec<BackfillRequest>, String> {
+    use self::backfill_queue::dsl;
+
+    dsl::backfill_queue
+        .filter(dsl::completed.eq(false))
+        .order(dsl::id.asc())
+        .load(conn)
+        .map_err(debug)
+}
+
+pub fn complete_backfill(id: i32, trades_found: i32, conn: &MysqlConnection) -> Result<(), String> {
+    use self::backfill_queue::dsl;
+
+    diesel::update(dsl::backfill_queue.filter(dsl::id.eq(id)))
+        .set((dsl::completed.eq(true), dsl::trades_found.eq(Some(trades_found))))
+        .execute(conn)
+        .map_err(debug)?;
+
+    Ok(())
+}
+
+/// Downloads each of the supplied queued gaps with the supplied function, which returns the number of trades that it
+/// stored, and marks the gap as completed.  A gap that fails to download stays queued so that the next backfill retries
+/// it, and the remaining gaps are downloaded regardless.  Returns the gaps that failed along with their errors.
+pub fn run_backfills<F>(
+    requests: Vec<BackfillRequest>, download: F, conn: &MysqlConnection
+) -> Result<Vec<(BackfillRequest, String)>, String>
+    where F: Fn(&BackfillRequest) -> Result<usize, String>
+{
+    let mut failed = Vec::new();
+    for request in requests {
+        println!("Backfilling {} from {} to {}...", request.pair, request.gap_start, request.gap_end);
+        let stored = match download(&request) {
+            Ok(stored) => stored,
+            Err(err) => {
+                println!("Error while backfilling {}: {}; moving on to the next gap.", request.pair, err);
+                failed.push((request, err));
+                continue;
+            },
+        };
+
+        if stored == 0 {
+            println!("No trades found in gap; the market was most likely illiquid during that time.");
+        } else {
+            println!("Stored {} trades that were missing.", stored);
+        }
+        complete_backfill(request.id, stored as i32, conn)?;
+    }
+
+    Ok(failed)
+}
+
+#[test]
+fn test_gap_finder() {
+    let time = |minutes: i64| NaiveDateTime::from_timestamp(1483228800 + (minutes * 60), 0);
+
+    let mut finder = GapFinder::new("BTC_XMR", Duration::hours(8));
+    for &minutes in &[0, 5, 10, 10 + (8 * 60), 11 + (16 * 60), 12 + (16 * 60)] {
+        finder.push(time(minutes));
+    }
+
+    assert_eq!(finder.gaps, vec![Gap { pair: String::from("BTC_XMR"), start: time(10 + (8 * 60)), end: time(11 + (16 * 60)) }]);
+}
+
+#[test]
+fn test_backfill_queue() {
+    use super::DbPool;
+    use config::Config;
+    use db_query::create_db_pool;
+
+    let pool = DbPool(create_db_pool(&Config::load().unwrap().database));
+    let conn = &*pool.get_conn();
+    create_backfill_queue_table(conn).unwrap();
+
+    let time = |hours: i64| NaiveDateTime::from_timestamp(1483228800 + (hours * 3600), 0);
+    let gap = |pair: &str, start: i64| Gap { pair: String::from(pair), start: time(start), end: time(start + 12) };
+    let (failing, succeeding) = (gap("TEST_BACKFILL_A", 0), gap("TEST_BACKFILL_B", 24));
+    assert!(queue_backfill(&failing, conn).unwrap());
+    assert!(queue_backfill(&succeeding, conn).unwrap());
+    // gaps that are already queued aren't queued again
+    assert!(!queue_backfill(&failing, conn).unwrap());
+
+    // only the gaps queued by this test are backfilled so that the rest of the queue is left alone
+    let queued = pending_backfills(conn).unwrap().into_iter()
+        .filter(|request| request.pair == failing.pair || request.pair == succeeding.pair)
+        .collect();
+    let failed = run_backfills(queued, |request| {
+        if request.pair == failing.pair { Err(String::from("502 Bad Gateway")) } else { Ok(3) }
+    }, conn).unwrap();
+    assert!(failed.iter().any(|&(ref request, _)| request.pair == failing.pair));
+    assert!(!failed.iter().any(|&(ref request, _)| request.pair == succeeding.pair));
+
+    // the failed gap stays queued while the other one is completed
+    let pending = pending_backfills(conn).unwrap();
+    assert!(pending.iter().any(|request| request.pair == failing.pair));
+    assert!(!pending.iter().any(|request| request.pair == succeeding.pair));
+
+    use self::backfill_queue::dsl;
+    let completed: BackfillRequest = dsl::backfill_queue.filter(dsl::pair.eq(&succeeding.pair)).first(conn).unwrap();
+    assert_eq!(completed.trades_found, Some(3));
+    diesel::delete(dsl::backfill_queue.filter(dsl::pair.like("TEST_BACKFILL_%"))).execute(conn).unwrap();
+}
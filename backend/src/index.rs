@@ -0,0 +1,318 @@
+//! Optional in-memory index of all stored rates.  Every pair's observations are loaded into compact sorted arrays so that
+//! rates can be resolved by binary search rather than by querying the database.  Observations from the most recent hour
+//! may still be changing as data is downloaded, so queries that reach into it always go to the database.
+//!
+//! The index is extended with newly downloaded observations periodically.  Each pair is loaded up to the time that the
+//! index covers and extended from there, so observations inside the most recent hour are picked up once they're old
+//! enough to be indexed.  Observations that are backfilled into windows that were already indexed are only picked up
+//! after the index is loaded again.
+//!
+//! An index can also be built directly from a set of observations, in which case it serves as a self-contained in-memory
+//! rate store.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::u32;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+
+use registry::PairMetadata;
+use store::RateStore;
+
+/// Number of days of observations loaded from the store at a time while loading the index
+const LOAD_WINDOW_DAYS: i64 = 30;
+/// Queries that reach into this many most recent minutes are always answered by the database.
+const RECENT_MINUTES: i64 = 60;
+
+/// The observations of a single pair, sorted by time
+#[derive(Default)]
+struct PairSeries {
+    /// Unix timestamps in seconds
+    times: Vec<u32>,
+    rates: Vec<f32>,
+}
+
+impl PairSeries {
+    fn get(&self, i: usize) -> (NaiveDateTime, f32) {
+        (NaiveDateTime::from_timestamp(self.times[i] as i64, 0), self.rates[i])
+    }
+
+    /// Returns the index of the first observation at or after the supplied time.
+    fn lower_bound(&self, time: NaiveDateTime) -> usize {
+        match self.times.binary_search(&to_index_time(time)) {
+            Ok(i) | Err(i) => i,
+        }
+    }
+
+    /// Returns the index of the first observation after the supplied time.
+    fn upper_bound(&self, time: NaiveDateTime) -> usize {
+        match self.times.binary_search(&to_index_time(time)) {
+            Ok(i) => i + 1,
+            Err(i) => i,
+        }
+    }
+}
+
+/// Converts a timestamp into the format stored in the index, clamping it to the range that can be represented.
+fn to_index_time(time: NaiveDateTime) -> u32 {
+    let seconds = time.timestamp();
+    if seconds < 0 {
+        0
+    } else if seconds > u32::MAX as i64 {
+        u32::MAX
+    } else {
+        seconds as u32
+    }
+}
+
+struct IndexState {
+    pairs: Vec<PairMetadata>,
+    series: HashMap<i32, PairSeries>,
+    /// The time up to which the observations of each pair have been loaded from the store
+    loaded_until: HashMap<i32, NaiveDateTime>,
+    /// Queries whose windows end after this time are answered by the database.  `None` if the index is disabled.
+    covered_until: Option<NaiveDateTime>,
+}
+
+impl IndexState {
+    fn new(covered_until: Option<NaiveDateTime>) -> IndexState {
+        IndexState { pairs: Vec::new(), series: HashMap::new(), loaded_until: HashMap::new(), covered_until: covered_until }
+    }
+}
+
+/// An in-memory index of the stored rates of every pair.  Clones of the index share its contents.
+#[derive(Clone)]
+pub struct RateIndex(Arc<RwLock<IndexState>>);
+
+/// Loads all observations of the given pair that were made after `after` and no later than `until` from the store, oldest
+/// first.
+fn load_series(
+    pair: &PairMetadata, after: NaiveDateTime, until: NaiveDateTime, store: &RateStore
+) -> Result<PairSeries, String> {
+    let mut series = PairSeries::default();
+    let (first, last) = match (pair.first_observation, pair.last_observation) {
+        (Some(first), Some(last)) => (first, if last < until { last } else { until }),
+        _ => { return Ok(series); },
+    };
+
+    // load the observations one window at a time rather than all of them at once
+    let mut start = if first > after { first } else { after + Duration::seconds(1) };
+    while start <= last {
+        let end = cmp::min(start + Duration::days(LOAD_WINDOW_DAYS), last);
+        for (time, rate) in store.range(pair.id, start, end)? {
+            series.times.push(to_index_time(time));
+            series.rates.push(rate);
+        }
+        start = end + Duration::seconds(1);
+    }
+
+    Ok(series)
+}
+
+impl RateIndex {
+    /// Creates an index that doesn't cover any observations, causing all queries to be answered by the database.
+    pub fn disabled() -> RateIndex {
+        RateIndex(Arc::new(RwLock::new(IndexState::new(None))))
+    }
+
+    /// Creates an index holding the supplied observations of each pair, which must be sorted from oldest to newest.  The
+    /// index covers everything up to its newest observation.
+    pub fn from_observations(pairs: Vec<(PairMetadata, Vec<(NaiveDateTime, f32)>)>) -> RateIndex {
+        let mut state = IndexState::new(None);
+        for (pair, observations) in pairs {
+            let mut series = PairSeries::default();
+            for (time, rate) in observations {
+                series.times.push(to_index_time(time));
+                series.rates.push(rate);
+                if state.covered_until.map(|covered_until| time > covered_until).unwrap_or(true) {
+                    state.covered_until = Some(time);
+                }
+            }
+            state.series.insert(pair.id, series);
+            state.pairs.push(pair);
+        }
+
+        RateIndex(Arc::new(RwLock::new(state)))
+    }
+
+    /// Loads the observations of every registered pair from the store into a new index.
+    pub fn load(store: &RateStore) -> Result<RateIndex, String> {
+        let index = RateIndex::disabled();
+        index.extend(store)?;
+        Ok(index)
+    }
+
+    /// Loads every observation stored in the store, including the most recent ones, into a new index that serves as a
+    /// self-contained in-memory rate store.
+    pub fn load_all(store: &RateStore) -> Result<RateIndex, String> {
+        let newest = store.list_pairs()?.iter().filter_map(|pair| pair.last_observation).max();
+        let index = RateIndex::disabled();
+        index.extend_until(store, newest.unwrap_or(NaiveDateTime::from_timestamp(0, 0)))?;
+        Ok(index)
+    }
+
+    /// Appends all observations that were stored since the index was last loaded or extended, including those of newly
+    /// registered pairs.  Returns the number of observations that were added.
+    pub fn extend(&self, store: &RateStore) -> Result<usize, String> {
+        self.extend_until(store, Utc::now().naive_utc() - Duration::minutes(RECENT_MINUTES))
+    }
+
+    /// Appends all observations up to `covered_until` that were stored after the point up to which each pair was last
+    /// loaded, and marks the index as covering everything up to that time.
+    fn extend_until(&self, store: &RateStore, covered_until: NaiveDateTime) -> Result<usize, String> {
+        let pairs = store.list_pairs()?;
+        let mut loaded = Vec::new();
+        for pair in &pairs {
+            let loaded_until = self.0.read().unwrap().loaded_until.get(&pair.id).cloned()
+                .unwrap_or(NaiveDateTime::from_timestamp(0, 0));
+            loaded.push((pair.id, load_series(pair, loaded_until, covered_until, store)?));
+        }
+
+        let mut state = self.0.write().unwrap();
+        state.pairs = pairs;
+        let mut count = 0;
+        for (pair_id, new_series) in loaded {
+            count += new_series.times.len();
+            state.loaded_until.insert(pair_id, covered_until);
+            let series = state.series.entry(pair_id).or_insert_with(PairSeries::default);
+            series.times.extend(new_series.times);
+            series.rates.extend(new_series.rates);
+        }
+        state.covered_until = Some(covered_until);
+
+        Ok(count)
+    }
+
+    /// Returns `true` if queries for windows of the given pair ending at the supplied time can be answered by the index.
+    /// Pairs that were registered after the index was last extended aren't covered at all.
+    pub fn covers(&self, pair_id: i32, end: NaiveDateTime) -> bool {
+        let state = self.0.read().unwrap();
+        match state.covered_until {
+            Some(covered_until) => end <= covered_until && state.series.contains_key(&pair_id),
+            None => false,
+        }
+    }
+
+    /// Returns all indexed pairs along with statistics about their indexed observations.
+    pub fn pairs(&self) -> Vec<PairMetadata> {
+        let state = self.0.read().unwrap();
+        state.pairs.iter()
+            .map(|pair| {
+                let times = state.series.get(&pair.id).map(|series| &series.times[..]).unwrap_or(&[]);
+                let time = |time: &u32| NaiveDateTime::from_timestamp(*time as i64, 0);
+                PairMetadata {
+                    first_observation: times.first().map(&time),
+                    last_observation: times.last().map(&time),
+                    observation_count: times.len() as i64,
+                    ..pair.clone()
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the total number of indexed observations.
+    pub fn len(&self) -> usize {
+        self.0.read().unwrap().series.values().map(|series| series.times.len()).sum()
+    }
+
+    /// Returns the last observation of the given pair at or before the supplied timestamp, but not before `search_start`.
+    pub fn before(
+        &self, pair_id: i32, timestamp: NaiveDateTime, search_start: NaiveDateTime
+    ) -> Option<(NaiveDateTime, f32)> {
+        let state = self.0.read().unwrap();
+        let series = match state.series.get(&pair_id) {
+            Some(series) => series,
+            None => { return None; },
+        };
+        match series.upper_bound(timestamp) {
+            0 => None,
+            i => {
+                let (time, rate) = series.get(i - 1);
+                if time >= search_start { Some((time, rate)) } else { None }
+            },
+        }
+    }
+
+    /// Returns the first observation of the given pair after the supplied timestamp, but not after `search_end`.
+    pub fn after(
+        &self, pair_id: i32, timestamp: NaiveDateTime, search_end: NaiveDateTime
+    ) -> Option<(NaiveDateTime, f32)> {
+        let state = self.0.read().unwrap();
+        let series = match state.series.get(&pair_id) {
+            Some(series) => series,
+            None => { return None; },
+        };
+        let i = series.upper_bound(timestamp);
+        if i >= series.times.len() {
+            return None;
+        }
+
+        let (time, rate) = series.get(i);
+        if time <= search_end { Some((time, rate)) } else { None }
+    }
+
+    /// Returns all observations of the given pair between `search_start` and the supplied timestamp, oldest first.
+    pub fn between(
+        &self, pair_id: i32, search_start: NaiveDateTime, timestamp: NaiveDateTime
+    ) -> Vec<(NaiveDateTime, f32)> {
+        let state = self.0.read().unwrap();
+        match state.series.get(&pair_id) {
+            Some(series) => (series.lower_bound(search_start)..series.upper_bound(timestamp))
+                .map(|i| series.get(i))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[test]
+fn test_index_lookups() {
+    let time = |minutes: i64| NaiveDateTime::from_timestamp(1483228800 + (minutes * 60), 0);
+
+    let mut series = PairSeries::default();
+    for &(minutes, rate) in &[(0, 1.), (10, 2.), (20, 3.)] {
+        series.times.push(to_index_time(time(minutes)));
+        series.rates.push(rate);
+    }
+    let mut state = IndexState::new(Some(time(30)));
+    state.series.insert(1, series);
+    let index = RateIndex(Arc::new(RwLock::new(state)));
+
+    assert_eq!(index.before(1, time(10), time(0)), Some((time(10), 2.)));
+    assert_eq!(index.before(1, time(9), time(5)), None);
+    assert_eq!(index.before(1, time(-1), time(-10)), None);
+    assert_eq!(index.after(1, time(10), time(30)), Some((time(20), 3.)));
+    assert_eq!(index.after(1, time(10), time(15)), None);
+    assert_eq!(index.after(1, time(20), time(30)), None);
+    assert_eq!(index.between(1, time(5), time(20)), vec![(time(10), 2.), (time(20), 3.)]);
+    assert_eq!(index.before(2, time(10), time(0)), None);
+
+    assert!(index.covers(1, time(30)));
+    assert!(!index.covers(1, time(31)));
+    assert!(!index.covers(2, time(30)));
+    assert!(!RateIndex::disabled().covers(1, time(0)));
+}
+
+#[test]
+fn test_index_extension() {
+    use normalized::PairInfo;
+
+    let time = |minutes: i64| NaiveDateTime::from_timestamp(1483228800 + (minutes * 60), 0);
+    let pair = PairMetadata::new(&PairInfo { id: 1, base: String::from("BTC"), quote: String::from("XMR") }, None);
+
+    let index = RateIndex::disabled();
+    let store = RateIndex::from_observations(vec![(pair.clone(), vec![(time(0), 1.), (time(20), 3.)])]);
+    assert_eq!(index.extend_until(&store, time(10)), Ok(1));
+    assert!(index.covers(1, time(10)));
+
+    // an observation that's stored later behind the newest stored one is still picked up once it's covered
+    let store = RateIndex::from_observations(vec![(pair, vec![(time(0), 1.), (time(15), 2.), (time(20), 3.)])]);
+    assert_eq!(index.extend_until(&store, time(30)), Ok(2));
+    assert_eq!(index.between(1, time(0), time(30)), vec![(time(0), 1.), (time(15), 2.), (time(20), 3.)]);
+    assert_eq!(index.extend_until(&store, time(40)), Ok(0));
+
+    let copy = RateIndex::load_all(&store).unwrap();
+    assert_eq!(copy.len(), 3);
+    assert!(copy.covers(1, time(20)));
+}
@@ -0,0 +1,115 @@
+//! Poloniex API backend.  See README.md for more information.
+
+#![feature(plugin, custom_derive, decl_macro)]
+#![plugin(rocket_codegen)]
+
+extern crate chrono;
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate diesel_codegen;
+extern crate hyper;
+extern crate hyper_native_tls;
+extern crate r2d2;
+#[cfg(feature = "postgres")]
+extern crate r2d2_diesel;
+extern crate r2d2_diesel_mysql;
+extern crate rayon;
+extern crate rocket;
+// #[macro_use]
+extern crate rocket_contrib;
+extern crate serde;
+extern crate serde_json;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use diesel::mysql::MysqlConnection;
+use r2d2::{ Pool, PooledConnection };
+use r2d2_diesel_mysql::ConnectionManager;
+
+mod cors;
+use cors::CORS;
+// mod schema;
+pub mod routes;
+pub mod config;
+pub mod db_query;
+pub mod error;
+pub mod cache;
+pub use cache::RateCache;
+mod feedback;
+pub mod prefiller;
+pub mod checkpoint;
+pub mod gaps;
+pub mod normalized;
+pub mod registry;
+pub mod candles;
+pub mod index;
+pub mod store;
+pub mod timestamp;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+use config::Config;
+use registry::PairRegistry;
+use store::RateStore;
+
+pub const MYSQL_DATE_FORMAT: &'static str = "%Y-%m-%d %H:%M:%S";
+
+/// Given a type that can be debug-formatted, returns a String that contains its debug-formatted version.
+pub fn debug<T>(x: T) -> String where T:Debug {
+    format!("{:?}", x)
+}
+
+pub struct DbPool(pub Pool<ConnectionManager<MysqlConnection>>);
+
+impl DbPool {
+    pub fn get_conn(&self) -> PooledConnection<ConnectionManager<MysqlConnection>> {
+        self.0.get().unwrap()
+    }
+}
+
+/// Creates the Rocket webserver instance with all of the API routes mounted and all managed state initialized.  The
+/// supplied rate cache and store are shared with the server so that they can be snapshotted and extended while the server
+/// is running.  The MySQL connection pool is only needed for the candle API and can be left out when rates are stored
+/// elsewhere.
+pub fn rocket(
+    config: &Config, rate_cache: RateCache, rate_store: Arc<RateStore>, db_pool: Option<DbPool>
+) -> rocket::Rocket {
+    let pair_registry = Arc::new(PairRegistry::load(&*rate_store).expect("Unable to load the pair registry!"));
+
+    let rocket = rocket::ignite()
+        .mount("/", routes![
+            routes::get_hist_rate,
+            routes::get_batch_hist_rates,
+            routes::get_rate_series,
+            routes::get_candles,
+            routes::get_currencies,
+            routes::get_cache_stats,
+            routes::refresh_currencies,
+            routes::submit_feedback,
+        ])
+        .catch(catchers![
+            error::bad_request,
+            error::not_found,
+            error::payload_too_large,
+            error::unprocessable_entity,
+            error::internal_error,
+        ])
+        .manage(pair_registry)
+        .manage(rate_cache)
+        .manage(rate_store)
+        .manage(config.limits.clone())
+        .manage(config.feedback.clone())
+        .manage(config.admin.clone())
+        .attach(CORS(config.cors.clone()));
+
+    match db_pool {
+        Some(db_pool) => rocket.manage(db_pool),
+        None => rocket,
+    }
+}
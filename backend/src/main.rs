@@ -1,97 +1,149 @@
 //! Poloniex API backend.  See README.md for more information.
 
-#![feature(plugin, custom_derive, decl_macro)]
-#![plugin(rocket_codegen)]
-
-extern crate chrono;
-extern crate diesel;
-#[macro_use]
-extern crate diesel_codegen;
-extern crate hyper;
-extern crate hyper_native_tls;
-extern crate r2d2;
-extern crate r2d2_diesel_mysql;
+extern crate ctrlc;
+extern crate polo_dashboard_backend;
 extern crate rayon;
-extern crate rocket;
-// #[macro_use]
-extern crate rocket_contrib;
-extern crate serde_json;
-#[macro_use]
-extern crate serde_derive;
-
-use std::fmt::Debug;
-use std::collections::HashMap;
-use std::collections::hash_map::Entry;
-use std::sync::{Arc, Mutex};
-
-use chrono::NaiveDateTime;
-use diesel::mysql::MysqlConnection;
-use r2d2::{ Pool, PooledConnection };
-use r2d2_diesel_mysql::ConnectionManager;
-
-mod cors;
-use cors::CORS;
-// mod schema;
-mod routes;
-mod secret;
-mod db_query;
-use db_query::HistRateQueryResult;
-mod feedback;
-
-pub const MYSQL_DATE_FORMAT: &'static str = "%Y-%m-%d %H:%M:%S";
-
-/// Given a type that can be debug-formatted, returns a String that contains its debug-formatted version.
-pub fn debug<T>(x: T) -> String where T:Debug {
-    format!("{:?}", x)
-}
 
-pub struct DbPool(Pool<ConnectionManager<MysqlConnection>>);
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use polo_dashboard_backend::{DbPool, RateCache};
+use polo_dashboard_backend::config::Config;
+use polo_dashboard_backend::db_query::create_db_pool;
+use polo_dashboard_backend::index::RateIndex;
+#[cfg(feature = "postgres")]
+use polo_dashboard_backend::postgres::PgStore;
+#[cfg(feature = "sqlite")]
+use polo_dashboard_backend::sqlite::SqliteStore;
+use polo_dashboard_backend::store::{IndexedStore, RateStore};
 
-impl DbPool {
-    pub fn get_conn(&self) -> PooledConnection<ConnectionManager<MysqlConnection>> {
-        self.0.get().unwrap()
+/// The number of seconds between two periodic snapshots of the rate cache
+const SNAPSHOT_INTERVAL_SECS: u64 = 10 * 60;
+/// The number of seconds between two extensions of the in-memory rate index with newly downloaded observations
+const INDEX_EXTEND_INTERVAL_SECS: u64 = 10 * 60;
+
+fn save_snapshot(rate_cache: &RateCache, path: &str) {
+    match rate_cache.save_snapshot(path) {
+        Ok(count) => println!("Saved {} cached rates to {}.", count, path),
+        Err(err) => println!("Error while saving the rate cache snapshot: {}", err),
     }
 }
 
-/// A structure to cache rates pulled from the database.  Since historical exchange rates don't change,
-/// we can safely cache the rates here to avoid extra database load.
-pub struct RateCache(Arc<Mutex<HashMap<(String, NaiveDateTime), Option<f32>>>>);
-
-impl RateCache {
-    fn new() -> RateCache {
-        RateCache(Arc::new(Mutex::new(HashMap::new())))
+/// Opens the store that rates are read from, which is selected by the `rates.store` setting.  It may be `mysql`,
+/// `sqlite:<path>` or `memory:<path>` if the backend was built with the `sqlite` feature, or the URL of a PostgreSQL database if it was built with the `postgres` feature.  Also returns the MySQL connection pool
+/// if rates are stored in MySQL.
+fn open_rate_store(config: &Config) -> (Arc<RateStore>, Option<DbPool>) {
+    let setting = &config.rates.store;
+    if config.uses_mysql() {
+        let pool = DbPool(create_db_pool(&config.database));
+        return (Arc::new(DbPool(pool.0.clone())), Some(pool));
     }
+    if setting.starts_with("memory:") {
+        return (load_memory_store(&setting["memory:".len()..]), None);
+    }
+    if setting.starts_with("sqlite:") {
+        return (open_sqlite_store(&setting["sqlite:".len()..]), None);
+    }
+
+    (open_postgres_store(setting, config.database.pool_size), None)
+}
 
-    /// Inserts an exchange rate into the cache
-    fn set(&self, pair: String, rate: Option<HistRateQueryResult>, timestamp: NaiveDateTime) {
-        self.0.lock().unwrap().insert((pair, timestamp), rate.map(|qr| qr.0));
+#[cfg(feature = "sqlite")]
+fn open_sqlite_store(path: &str) -> Arc<RateStore> {
+    Arc::new(SqliteStore::open(path).expect("Unable to open the SQLite rate store!"))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_sqlite_store(_: &str) -> Arc<RateStore> {
+    panic!("The backend must be built with the `sqlite` feature to read rates from SQLite!");
+}
+
+/// Loads every rate stored in the SQLite file at the given path into memory and serves them from there without touching
+/// the file again.
+#[cfg(feature = "sqlite")]
+fn load_memory_store(path: &str) -> Arc<RateStore> {
+    let sqlite_store = SqliteStore::open(path).expect("Unable to open the SQLite rate store!");
+    let rate_index = RateIndex::load_all(&sqlite_store).expect("Unable to load the rates into memory!");
+    println!("Loaded {} rates from {} into memory.", rate_index.len(), path);
+    Arc::new(rate_index)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn load_memory_store(_: &str) -> Arc<RateStore> {
+    panic!("The backend must be built with the `sqlite` feature to load rates into memory from SQLite!");
+}
+
+#[cfg(feature = "postgres")]
+fn open_postgres_store(url: &str, pool_size: u32) -> Arc<RateStore> {
+    Arc::new(PgStore::connect(url, pool_size).expect("Unable to connect to the PostgreSQL rate store!"))
+}
+
+#[cfg(not(feature = "postgres"))]
+fn open_postgres_store(_: &str, _: u32) -> Arc<RateStore> {
+    panic!("The backend must be built with the `postgres` feature to read rates from PostgreSQL!");
+}
+
+/// Loads all rates from the store into memory if the `rates.index` setting is enabled, periodically adding newly stored
+/// rates to the index.  Otherwise, returns the store itself.
+fn index_rate_store(config: &Config, rate_store: Arc<RateStore>) -> Arc<RateStore> {
+    if !config.rates.index {
+        return rate_store;
     }
 
-    /// Attempts to retrieve a cached value from the inner `HashMap`
-    fn get(&self, pair: String, timestamp: NaiveDateTime) -> Option<Option<f32>> {
-        match self.0.lock().unwrap().entry((pair, timestamp)) {
-            Entry::Occupied(val) => Some(val.get().clone()),
-            _ => None,
+    let rate_index = RateIndex::load(&*rate_store).expect("Unable to load the rate index!");
+    println!("Loaded {} rates into the rate index.", rate_index.len());
+
+    let (extended_index, source) = (rate_index.clone(), rate_store.clone());
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(INDEX_EXTEND_INTERVAL_SECS));
+        match extended_index.extend(&*source) {
+            Ok(count) => println!("Added {} rates to the rate index.", count),
+            Err(err) => println!("Error while extending the rate index: {}", err),
         }
-    }
+    });
+
+    Arc::new(IndexedStore { index: rate_index, fallback: rate_store })
 }
 
 fn main() {
-    // initialize Rayon threadpool with custom configuration with 24 "threads" which actually translates to MySQL Connections
-    rayon::initialize(rayon::Configuration::new().num_threads(24)).expect("Unable to initialize Rayon threadpool!");
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            println!("Invalid configuration: {}", err);
+            process::exit(1);
+        },
+    };
+
+    // initialize Rayon threadpool with custom configuration with the configured number of "threads" which actually
+    // translates to MySQL Connections
+    rayon::initialize(rayon::Configuration::new().num_threads(config.rates.threads))
+        .expect("Unable to initialize Rayon threadpool!");
+
+    // restore the rate cache from the last run so that the database isn't flooded with requests after a restart
+    let snapshot_path = config.cache.snapshot.clone();
+    let rate_cache = RateCache::new(config.cache.capacity);
+    match rate_cache.load_snapshot(&snapshot_path) {
+        Ok(count) => println!("Loaded {} cached rates from {}.", count, snapshot_path),
+        Err(err) => println!("Error while loading the rate cache snapshot: {}", err),
+    }
+
+    let (periodic_cache, periodic_path) = (rate_cache.clone(), snapshot_path.clone());
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(SNAPSHOT_INTERVAL_SECS));
+        save_snapshot(&periodic_cache, &periodic_path);
+    });
+
+    let (shutdown_cache, shutdown_path) = (rate_cache.clone(), snapshot_path.clone());
+    ctrlc::set_handler(move || {
+        save_snapshot(&shutdown_cache, &shutdown_path);
+        process::exit(0);
+    }).expect("Unable to install the shutdown handler!");
+
+    let (rate_store, db_pool) = open_rate_store(&config);
+    let rate_store = index_rate_store(&config, rate_store);
 
     // initialize the Rocket webserver
-    rocket::ignite()
-        .mount("/", routes![
-            routes::rate_options_handler,
-            routes::batch_rate_options_handler,
-            routes::feedback_options_handler,
-            routes::get_hist_rate,
-            routes::get_batch_hist_rates,
-            routes::submit_feedback,
-        ])
-        .manage(DbPool(db_query::create_db_pool()))
-        .manage(RateCache::new())
-        .attach(CORS())
-        .launch();
+    polo_dashboard_backend::rocket(&config, rate_cache, rate_store, db_pool).launch();
 }
@@ -0,0 +1,257 @@
+//! Normalized storage for historical rates.  Rather than one `trades_<base>_<quote>` table per pair, all observations are
+//! stored in a single `rates` table keyed by pair ID and timestamp, with the pairs and their currencies stored in the
+//! `pairs` and `currencies` lookup tables.  This allows all rate queries to be expressed as typed Diesel queries.
+
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+use diesel;
+use diesel::prelude::*;
+use diesel::mysql::MysqlConnection;
+
+use super::{debug, MYSQL_DATE_FORMAT};
+use db_query::list_trade_tables;
+
+/// Number of rows inserted per `INSERT` statement to keep statements well under MySQL's maximum packet size
+const INSERT_BATCH_SIZE: usize = 5000;
+
+table! {
+    currencies (id) {
+        id -> Integer,
+        symbol -> Varchar,
+    }
+}
+
+table! {
+    pairs (id) {
+        id -> Integer,
+        base_id -> Integer,
+        quote_id -> Integer,
+    }
+}
+
+table! {
+    rates (pair_id, trade_time) {
+        pair_id -> Integer,
+        trade_time -> Timestamp,
+        rate -> Float,
+    }
+}
+
+#[derive(Insertable)]
+#[table_name="currencies"]
+struct NewCurrency<'a> {
+    symbol: &'a str,
+}
+
+#[derive(Insertable)]
+#[table_name="pairs"]
+struct NewPair {
+    base_id: i32,
+    quote_id: i32,
+}
+
+
+/// A pair that has observations stored in the `rates` table
+#[derive(Clone, Debug, PartialEq)]
+pub struct PairInfo {
+    pub id: i32,
+    pub base: String,
+    pub quote: String,
+}
+
+impl PairInfo {
+    /// Returns the name of the pair in the format used by Poloniex, for example "BTC_XMR".
+    pub fn name(&self) -> String {
+        format!("{}_{}", self.base, self.quote)
+    }
+}
+
+/// Splits a pair name in the format used by Poloniex ("BTC_XMR") into its base and quote currencies.
+pub fn split_pair_name(name: &str) -> Option<(&str, &str)> {
+    let mut split = name.splitn(2, '_');
+    match (split.next(), split.next()) {
+        (Some(base), Some(quote)) if !base.is_empty() && !quote.is_empty() => Some((base, quote)),
+        _ => None,
+    }
+}
+
+pub fn create_tables(conn: &MysqlConnection) -> Result<(), String> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS `currencies` (
+            id INT PRIMARY KEY AUTO_INCREMENT NOT NULL,
+            symbol VARCHAR(16) NOT NULL,
+            UNIQUE (symbol)
+        );"
+    ).map_err(debug)?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS `pairs` (
+            id INT PRIMARY KEY AUTO_INCREMENT NOT NULL,
+            base_id INT NOT NULL,
+            quote_id INT NOT NULL,
+            UNIQUE (base_id, quote_id),
+            FOREIGN KEY (base_id) REFERENCES currencies(id),
+            FOREIGN KEY (quote_id) REFERENCES currencies(id)
+        );"
+    ).map_err(debug)?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS `rates` (
+            pair_id INT NOT NULL,
+            trade_time TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+            rate FLOAT NOT NULL,
+            PRIMARY KEY (pair_id, trade_time),
+            FOREIGN KEY (pair_id) REFERENCES pairs(id)
+        );"
+    ).map_err(debug)?;
+
+    Ok(())
+}
+
+fn find_currency_id(symbol: &str, conn: &MysqlConnection) -> Result<Option<i32>, String> {
+    use self::currencies::dsl;
+
+    dsl::currencies
+        .filter(dsl::symbol.eq(symbol))
+        .select(dsl::id)
+        .first(conn)
+        .optional()
+        .map_err(debug)
+}
+
+/// Returns the ID of the pair with the given base and quote currencies if it exists.
+pub fn find_pair_id(base: &str, quote: &str, conn: &MysqlConnection) -> Result<Option<i32>, String> {
+    use self::pairs::dsl;
+
+    let (base_id, quote_id) = match (find_currency_id(base, conn)?, find_currency_id(quote, conn)?) {
+        (Some(base_id), Some(quote_id)) => (base_id, quote_id),
+        _ => { return Ok(None); },
+    };
+
+    dsl::pairs
+        .filter(dsl::base_id.eq(base_id))
+        .filter(dsl::quote_id.eq(quote_id))
+        .select(dsl::id)
+        .first(conn)
+        .optional()
+        .map_err(debug)
+}
+
+/// Returns the ID of the pair with the given base and quote currencies, creating the pair and its currencies if they
+/// don't already exist.
+pub fn register_pair(base: &str, quote: &str, conn: &MysqlConnection) -> Result<i32, String> {
+    let mut ids = Vec::with_capacity(2);
+    for symbol in &[base, quote] {
+        let id = match find_currency_id(symbol, conn)? {
+            Some(id) => id,
+            None => {
+                diesel::insert(&NewCurrency { symbol: symbol })
+                    .into(currencies::table)
+                    .execute(conn)
+                    .map_err(debug)?;
+                find_currency_id(symbol, conn)?.ok_or(format!("Failed to insert currency {}!", symbol))?
+            },
+        };
+        ids.push(id);
+    }
+
+    if let Some(id) = find_pair_id(base, quote, conn)? {
+        return Ok(id);
+    }
+    diesel::insert(&NewPair { base_id: ids[0], quote_id: ids[1] })
+        .into(pairs::table)
+        .execute(conn)
+        .map_err(debug)?;
+    find_pair_id(base, quote, conn)?.ok_or(format!("Failed to insert pair {}_{}!", base, quote))
+}
+
+/// Returns all pairs that have been registered, sorted by name.
+pub fn list_pairs(conn: &MysqlConnection) -> Result<Vec<PairInfo>, String> {
+    let symbols: HashMap<i32, String> = currencies::table
+        .load::<(i32, String)>(conn)
+        .map_err(debug)?
+        .into_iter()
+        .collect();
+
+    let mut pair_infos: Vec<PairInfo> = pairs::table
+        .load::<(i32, i32, i32)>(conn)
+        .map_err(debug)?
+        .into_iter()
+        .filter_map(|(id, base_id, quote_id)| match (symbols.get(&base_id), symbols.get(&quote_id)) {
+            (Some(base), Some(quote)) => Some(PairInfo { id: id, base: base.clone(), quote: quote.clone() }),
+            _ => None,
+        })
+        .collect();
+    pair_infos.sort_by(|a, b| a.name().cmp(&b.name()));
+
+    Ok(pair_infos)
+}
+
+/// Returns the time of the most recent observation stored for the given pair.
+pub fn latest_rate_time(pair_id: i32, conn: &MysqlConnection) -> Result<Option<NaiveDateTime>, String> {
+    use self::rates::dsl;
+
+    dsl::rates
+        .filter(dsl::pair_id.eq(pair_id))
+        .order(dsl::trade_time.desc())
+        .select(dsl::trade_time)
+        .first(conn)
+        .optional()
+        .map_err(debug)
+}
+
+/// Stores the supplied observations, replacing the rates of any that are already stored for the same times.  Other stored
+/// observations are kept, so storing a coarse series (like daily fiat rates) never removes finer observations that were
+/// previously stored within its window.
+pub fn insert_rates(pair_id: i32, observations: &[(NaiveDateTime, f32)], conn: &MysqlConnection) -> Result<(), String> {
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        for batch in observations.chunks(INSERT_BATCH_SIZE) {
+            // Diesel can't express upserts for MySQL.  All values are numbers and timestamps rather than user input.
+            let values: Vec<String> = batch.iter()
+                .map(|&(time, rate)| format!("({}, '{}', {})", pair_id, time.format(MYSQL_DATE_FORMAT), rate))
+                .collect();
+            conn.execute(&format!(
+                "INSERT INTO `rates` (pair_id, trade_time, rate) VALUES {} ON DUPLICATE KEY UPDATE rate = VALUES(rate)",
+                values.join(", ")
+            ))?;
+        }
+        Ok(())
+    }).map_err(debug)
+}
+
+/// Copies the contents of every legacy `trades_<base>_<quote>` table into the normalized tables, registering their pairs.
+/// Observations that have already been copied are skipped, so it's safe to run the migration repeatedly.  Returns the
+/// number of rows that were copied for each table.
+pub fn migrate_trade_tables(conn: &MysqlConnection) -> Result<Vec<(String, usize)>, String> {
+    create_tables(conn)?;
+
+    let mut copied = Vec::new();
+    for table_name in list_trade_tables(conn)? {
+        let (base, quote) = match split_pair_name(&table_name["trades_".len()..]) {
+            Some(split) => split,
+            None => {
+                println!("Skipping table {} since its name doesn't contain a valid pair.", table_name);
+                continue;
+            },
+        };
+        let pair_id = register_pair(base, quote, conn)?;
+
+        // Diesel can't express queries against dynamically named tables.  Both the table name and pair ID come from the
+        // database itself rather than from user input.
+        let count = conn.execute(&format!(
+            "INSERT IGNORE INTO `rates` (pair_id, trade_time, rate) SELECT {}, trade_time, rate FROM `{}`",
+            pair_id, table_name
+        )).map_err(debug)?;
+        println!("Copied {} rows from {}.", count, table_name);
+        copied.push((table_name.clone(), count));
+    }
+
+    Ok(copied)
+}
+
+#[test]
+fn test_split_pair_name() {
+    assert_eq!(split_pair_name("BTC_XMR"), Some(("BTC", "XMR")));
+    assert_eq!(split_pair_name("USDT_BTC"), Some(("USDT", "BTC")));
+    assert_eq!(split_pair_name("BTC"), None);
+    assert_eq!(split_pair_name("_XMR"), None);
+}
@@ -0,0 +1,700 @@
+//! Native port of the prefiller utility located in `/prefiller/index.js`.  Downloads the full trade history of every
+//! pair listed on Poloniex as well as historical BTC/fiat rates from Coinbase and stores them in the database.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::mysql::MysqlConnection;
+use hyper::Client;
+use hyper::status::StatusCode;
+use hyper::net::HttpsConnector;
+use hyper_native_tls::NativeTlsClient;
+use serde_json;
+
+use super::{debug, DbPool, MYSQL_DATE_FORMAT};
+use checkpoint::{self, Checkpoint};
+use db_query::BASE_CURRENCIES;
+use normalized::{self, split_pair_name};
+
+pub const POLONIEX_API_URL: &'static str = "https://poloniex.com/public";
+pub const COINBASE_API_URL: &'static str = "https://api.coinbase.com/v2";
+
+/// The maximum number of trades that Poloniex returns for a single `returnTradeHistory` request
+pub const MAX_TRADES_PER_REQUEST: usize = 50000;
+const SECONDS_IN_A_YEAR: i64 = 31556926;
+/// Poloniex refuses requests for windows larger than a year, so segments are kept slightly smaller than that
+const SEGMENT_SIZE: i64 = SECONDS_IN_A_YEAR * 99 / 100;
+/// Trades that occurred less than this many seconds after the previously stored trade are dropped
+const MIN_TRADE_SPACING_SECONDS: i64 = 48;
+/// The point at which downloads start for pairs that have no stored data (2010-01-01)
+const HISTORY_START_TIMESTAMP: i64 = 1262304000;
+/// Number of times that a failed request is retried before giving up on the pair
+const MAX_RETRIES: usize = 5;
+
+/// A single trade as returned by the `returnTradeHistory` endpoint of the Poloniex API
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct RawTrade {
+    globalTradeID: i64,
+    date: String,
+    rate: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TradeHistoryResponse {
+    Trades(Vec<RawTrade>),
+    Error { error: String },
+}
+
+#[derive(Deserialize)]
+struct CoinbasePrice {
+    price: String,
+    time: String,
+}
+
+#[derive(Deserialize)]
+struct CoinbasePrices {
+    prices: Vec<CoinbasePrice>,
+}
+
+#[derive(Deserialize)]
+struct CoinbaseResponse {
+    data: CoinbasePrices,
+}
+
+/// A trade that has been parsed out of the API response and is ready to be inserted into the database
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Trade {
+    pub id: i64,
+    pub time: NaiveDateTime,
+    pub rate: f32,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PrefillError {
+    /// Poloniex doesn't have any trade history for the requested pair
+    InvalidPair,
+    /// We've exceeded the Poloniex API rate limit and need to back off before making more requests
+    RateLimited,
+    Other(String),
+}
+
+/// Amounts of time to wait between requests to avoid tripping the API rate limits
+#[derive(Clone, Copy)]
+pub struct Delays {
+    /// Between successive chunks of the same pair
+    pub chunk: Duration,
+    /// After a pair has been completely downloaded
+    pub pair: Duration,
+    /// After the API informs us that we've exceeded the rate limit
+    pub rate_limit: Duration,
+    /// After a request fails for any other reason
+    pub retry: Duration,
+}
+
+impl Default for Delays {
+    fn default() -> Delays {
+        Delays {
+            chunk: Duration::from_millis(10254),
+            pair: Duration::from_millis(3012),
+            rate_limit: Duration::from_secs(180),
+            retry: Duration::from_millis(10169),
+        }
+    }
+}
+
+/// Returns the name of the Poloniex pair used to download the BTC exchange history of the given currency.
+pub fn pair_for_currency(currency: &str) -> String {
+    // have to make special considerations for USDT/BTC
+    if currency == "USDT" {
+        String::from("USDT_BTC")
+    } else {
+        format!("BTC_{}", currency)
+    }
+}
+
+/// Removes trades from the supplied list so that all remaining trades are at least 48 seconds apart.  The returned
+/// trades are sorted from oldest to newest.
+pub fn downsample(mut trades: Vec<Trade>) -> Vec<Trade> {
+    trades.sort_by_key(|trade| trade.id);
+
+    let mut sampled: Vec<Trade> = Vec::with_capacity(trades.len());
+    for trade in trades {
+        let keep = match sampled.last() {
+            Some(last) => trade.time.signed_duration_since(last.time).num_seconds() >= MIN_TRADE_SPACING_SECONDS,
+            None => true,
+        };
+        if keep {
+            sampled.push(trade);
+        }
+    }
+
+    sampled
+}
+
+/// The window of time that is currently being downloaded for a pair.  Since the API truncates results that contain more
+/// than 50,000 trades, oversized windows are downloaded by repeatedly moving `end` back in time ("backtracking") until the
+/// whole window has been retrieved.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Segment {
+    pub start: i64,
+    pub end: i64,
+    /// The most recent point that has been downloaded; the next segment starts after it once backtracking is finished.
+    pub max_end: i64,
+    /// The point at which the download for the pair is complete
+    pub final_end: i64,
+    pub backtracking: bool,
+}
+
+impl Segment {
+    pub fn new(start: i64, final_end: i64) -> Segment {
+        // make sure that we're requesting less than a year's worth of trades to start off
+        let end = if final_end - start > SECONDS_IN_A_YEAR { start + SEGMENT_SIZE } else { final_end };
+
+        Segment {
+            start: start,
+            end: end,
+            max_end: end,
+            final_end: final_end,
+            backtracking: false,
+        }
+    }
+
+    /// Moves the segment forward after a chunk has been downloaded.  `trade_count` is the number of trades returned for the
+    /// chunk and `earliest` is the timestamp of the oldest of them.  Returns `false` once the pair is fully downloaded.
+    pub fn advance(&mut self, trade_count: usize, earliest: Option<i64>, page_size: usize) -> bool {
+        if self.max_end < self.end {
+            self.max_end = self.end;
+        }
+
+        match earliest {
+            Some(earliest) if trade_count >= page_size => {
+                // the result was truncated, so download what's missing before going on
+                self.end = earliest - 1;
+                self.backtracking = true;
+                return true;
+            },
+            _ => (),
+        }
+
+        if self.backtracking {
+            // we've finished the oversized segment and can continue after the most recent point downloaded
+            self.start = self.max_end + 1;
+            self.backtracking = false;
+        } else {
+            self.start = self.end + 1;
+        }
+
+        if self.final_end - self.end > SECONDS_IN_A_YEAR {
+            self.end = self.start + SEGMENT_SIZE;
+            true
+        } else if self.end >= self.final_end || self.start >= self.final_end {
+            false
+        } else {
+            self.end = self.final_end;
+            true
+        }
+    }
+}
+
+/// Storage for the downloaded data.  Implemented for `DbPool` to store data in the normalized `rates` table.
+pub trait TradeStore {
+    /// Creates the tables used to store rates and download checkpoints if they don't already exist.
+    fn create_tables(&self) -> Result<(), String>;
+
+    /// Registers the given pair so that rates can be stored for it.
+    fn register_pair(&self, pair: &str) -> Result<(), String>;
+
+    /// Returns the time of the most recent observation stored for the given pair.
+    fn latest_trade_time(&self, pair: &str) -> Result<Option<NaiveDateTime>, String>;
+
+    /// Stores the given observations, which are sorted from oldest to newest, replacing the rates of any that were
+    /// previously stored for the same times.  Other stored observations within their window are kept.
+    fn insert_rates(&self, pair: &str, rates: &[(NaiveDateTime, f32)]) -> Result<(), String>;
+
+    fn load_checkpoint(&self, pair: &str) -> Result<Option<Checkpoint>, String>;
+
+    fn save_checkpoint(&self, checkpoint: &Checkpoint) -> Result<(), String>;
+
+    /// Returns the checkpoints of all pairs, sorted by pair.
+    fn list_checkpoints(&self) -> Result<Vec<Checkpoint>, String>;
+}
+
+/// Returns the ID of the pair with the supplied name (formatted like "BTC_XMR") if it has been registered.
+fn find_pair_id_by_name(pair: &str, conn: &MysqlConnection) -> Result<Option<i32>, String> {
+    match split_pair_name(pair) {
+        Some((base, quote)) => normalized::find_pair_id(base, quote, conn),
+        None => Err(format!("Invalid pair name: {}", pair)),
+    }
+}
+
+impl TradeStore for DbPool {
+    fn create_tables(&self) -> Result<(), String> {
+        let conn = &*self.get_conn();
+        normalized::create_tables(conn)?;
+        checkpoint::create_checkpoints_table(conn)
+    }
+
+    fn register_pair(&self, pair: &str) -> Result<(), String> {
+        let (base, quote) = split_pair_name(pair).ok_or(format!("Invalid pair name: {}", pair))?;
+        normalized::register_pair(base, quote, &*self.get_conn())?;
+        Ok(())
+    }
+
+    fn latest_trade_time(&self, pair: &str) -> Result<Option<NaiveDateTime>, String> {
+        let conn = &*self.get_conn();
+        match find_pair_id_by_name(pair, conn)? {
+            Some(pair_id) => normalized::latest_rate_time(pair_id, conn),
+            None => Ok(None),
+        }
+    }
+
+    fn insert_rates(&self, pair: &str, rates: &[(NaiveDateTime, f32)]) -> Result<(), String> {
+        let conn = &*self.get_conn();
+        let pair_id = find_pair_id_by_name(pair, conn)?
+            .ok_or(format!("Attempted to store rates for unregistered pair {}!", pair))?;
+        normalized::insert_rates(pair_id, rates, conn)
+    }
+
+    fn load_checkpoint(&self, pair: &str) -> Result<Option<Checkpoint>, String> {
+        checkpoint::load_checkpoint(pair, &*self.get_conn())
+    }
+
+    fn save_checkpoint(&self, checkpoint: &Checkpoint) -> Result<(), String> {
+        checkpoint::save_checkpoint(checkpoint, &*self.get_conn())
+    }
+
+    fn list_checkpoints(&self) -> Result<Vec<Checkpoint>, String> {
+        checkpoint::list_checkpoints(&*self.get_conn())
+    }
+}
+
+/// HTTP client for the Poloniex and Coinbase public APIs
+pub struct PoloClient {
+    client: Client,
+    poloniex_url: String,
+    coinbase_url: String,
+}
+
+impl PoloClient {
+    pub fn new(poloniex_url: &str, coinbase_url: &str) -> PoloClient {
+        let ssl = NativeTlsClient::new().unwrap();
+        let mut client = Client::with_connector(HttpsConnector::new(ssl));
+        client.set_read_timeout(Some(Duration::from_secs(15)));
+
+        PoloClient {
+            client: client,
+            poloniex_url: String::from(poloniex_url),
+            coinbase_url: String::from(coinbase_url),
+        }
+    }
+
+    fn get(&self, url: &str) -> Result<String, PrefillError> {
+        let mut res = self.client.get(url).send().map_err(|err| PrefillError::Other(debug(err)))?;
+        if res.status == StatusCode::TooManyRequests {
+            return Err(PrefillError::RateLimited);
+        }
+
+        let mut buf = String::new();
+        res.read_to_string(&mut buf).map_err(|err| PrefillError::Other(debug(err)))?;
+        Ok(buf)
+    }
+
+    /// Returns the symbols of all currencies that are listed on Poloniex.
+    pub fn fetch_currencies(&self) -> Result<Vec<String>, PrefillError> {
+        let body = self.get(&format!("{}?command=returnCurrencies", self.poloniex_url))?;
+        let currencies = serde_json::from_str::<HashMap<String, serde_json::Value>>(&body)
+            .map_err(|err| PrefillError::Other(format!("Error parsing currencies from JSON: {:?}", err)))?;
+
+        let mut symbols: Vec<String> = currencies.into_iter().map(|(symbol, _)| symbol).collect();
+        symbols.sort();
+        Ok(symbols)
+    }
+
+    /// Queries the public API for the trades of a pair between the two supplied timestamps.  Results are limited to 50,000
+    /// trades and the window must be less than a year in size.  If there are more than 50,000 trades in the window, the
+    /// oldest ones are truncated.
+    pub fn fetch_trade_history(&self, pair: &str, start: i64, end: i64) -> Result<Vec<Trade>, PrefillError> {
+        let url = format!(
+            "{}?command=returnTradeHistory&currencyPair={}&start={}&end={}",
+            self.poloniex_url, pair, start, end
+        );
+        let body = self.get(&url)?;
+
+        match serde_json::from_str::<TradeHistoryResponse>(&body) {
+            Ok(TradeHistoryResponse::Trades(raw_trades)) => raw_trades.into_iter()
+                .map(|raw| Ok(Trade {
+                    id: raw.globalTradeID,
+                    time: NaiveDateTime::parse_from_str(&raw.date, MYSQL_DATE_FORMAT)
+                        .map_err(|err| PrefillError::Other(debug(err)))?,
+                    rate: raw.rate.parse().map_err(|err| PrefillError::Other(debug(err)))?,
+                }))
+                .collect(),
+            Ok(TradeHistoryResponse::Error { error }) => if error == "Invalid currency pair." {
+                Err(PrefillError::InvalidPair)
+            } else if error.contains("Too many requests") {
+                Err(PrefillError::RateLimited)
+            } else {
+                Err(PrefillError::Other(error))
+            },
+            Err(err) => Err(PrefillError::Other(format!("Error parsing trade history from JSON: {:?}", err))),
+        }
+    }
+
+    /// Fetches historical BTC prices in terms of the given fiat currency from Coinbase.  `period` is either "all" for
+    /// daily prices over the entire history or "hour" for minutely prices over the last hour.
+    pub fn fetch_fiat_history(&self, currency: &str, period: &str) -> Result<Vec<(NaiveDateTime, f32)>, PrefillError> {
+        let url = format!("{}/prices/BTC-{}/historic?period={}", self.coinbase_url, currency, period);
+        let body = self.get(&url)?;
+        let res = serde_json::from_str::<CoinbaseResponse>(&body)
+            .map_err(|err| PrefillError::Other(format!("Error parsing Coinbase response from JSON: {:?}", err)))?;
+
+        let mut prices = res.data.prices.into_iter()
+            .map(|price| Ok((
+                DateTime::parse_from_rfc3339(&price.time).map_err(|err| PrefillError::Other(debug(err)))?.naive_utc(),
+                price.price.parse().map_err(|err| PrefillError::Other(debug(err)))?,
+            )))
+            .collect::<Result<Vec<(NaiveDateTime, f32)>, PrefillError>>()?;
+        // prices are returned newest first
+        prices.sort_by_key(|&(time, _)| time);
+        Ok(prices)
+    }
+}
+
+pub struct Prefiller<S: TradeStore> {
+    pub client: PoloClient,
+    pub store: S,
+    pub delays: Delays,
+    /// The maximum number of trades returned by the API for a single request
+    pub page_size: usize,
+}
+
+impl<S: TradeStore> Prefiller<S> {
+    pub fn new(client: PoloClient, store: S) -> Prefiller<S> {
+        Prefiller {
+            client: client,
+            store: store,
+            delays: Delays::default(),
+            page_size: MAX_TRADES_PER_REQUEST,
+        }
+    }
+
+    /// Calls the supplied function, retrying it if it fails.  If the rate limit was exceeded, waits for the rate limit delay
+    /// before retrying; this doesn't count against the maximum number of retries.
+    fn with_retries<T, F>(&self, f: F) -> Result<T, PrefillError> where F: Fn() -> Result<T, PrefillError> {
+        let mut attempts = 0;
+        loop {
+            match f() {
+                Err(PrefillError::RateLimited) => {
+                    println!("Exceeded Poloniex rate limit; sleeping for {} seconds...", self.delays.rate_limit.as_secs());
+                    thread::sleep(self.delays.rate_limit);
+                },
+                Err(PrefillError::Other(err)) => {
+                    attempts += 1;
+                    if attempts > MAX_RETRIES {
+                        return Err(PrefillError::Other(err));
+                    }
+                    println!("Error while making request: {}; trying again...", err);
+                    thread::sleep(self.delays.retry);
+                },
+                res => { return res; },
+            }
+        }
+    }
+
+    /// Downloads the full history of BTC prices for all fiat currencies, storing them in the database.
+    pub fn download_fiat_rates(&self) -> Result<(), PrefillError> {
+        for currency in BASE_CURRENCIES {
+            let pair = format!("BTC_{}", currency);
+            self.store.register_pair(&pair).map_err(PrefillError::Other)?;
+
+            for period in &["all", "hour"] {
+                println!("Downloading {} data from coinbase with period {}...", pair, period);
+                let rates = self.with_retries(|| self.client.fetch_fiat_history(currency, period))?;
+                self.store.insert_rates(&pair, &rates).map_err(PrefillError::Other)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Determines the segment at which the download for the given pair should start.  If a previous download of the pair
+    /// was interrupted, it is resumed from its checkpoint.  `final_end` is the point at which the download should end.
+    fn initial_segment(&self, pair: &str, final_end: i64) -> Result<Segment, PrefillError> {
+        match self.store.load_checkpoint(pair).map_err(PrefillError::Other)? {
+            Some(ref checkpoint) if !checkpoint.complete => {
+                println!("Resuming interrupted download for pair {} from checkpoint: {:?}", pair, checkpoint);
+                let mut segment = checkpoint.segment();
+                if segment.final_end < final_end {
+                    segment.final_end = final_end;
+                }
+                return Ok(segment);
+            },
+            _ => (),
+        }
+
+        // If data already exists in the database, pick the most recent point as the starting point.  If not, start in 2010
+        // so that we know we don't miss any history.
+        let start = match self.store.latest_trade_time(pair).map_err(PrefillError::Other)? {
+            Some(time) => time.timestamp(),
+            None => HISTORY_START_TIMESTAMP,
+        };
+        Ok(Segment::new(start, final_end))
+    }
+
+    /// Downloads all trades for the given pair that occurred after the most recent trade stored, storing them in the
+    /// database.  `now` is the current Unix timestamp.  Returns the number of trades that were stored.
+    ///
+    /// A checkpoint is saved after every chunk, so if the download is interrupted the next call resumes it exactly.
+    pub fn download_pair(&self, pair: &str, now: i64) -> Result<usize, PrefillError> {
+        // end at our current timestamp plus 24 hours
+        let segment = self.initial_segment(pair, now + 86400)?;
+        self.download_segment(pair, segment, true)
+    }
+
+    /// Downloads all trades for the given pair between the two supplied timestamps, storing them in the database.  This
+    /// doesn't affect the pair's checkpoint.  Returns the number of trades that were stored.
+    pub fn download_window(&self, pair: &str, start: i64, end: i64) -> Result<usize, PrefillError> {
+        self.download_segment(pair, Segment::new(start, end), false)
+    }
+
+    /// Downloads chunks starting with the supplied segment until its `final_end` has been reached, optionally saving a
+    /// checkpoint after every chunk.  Returns the number of trades that were stored.
+    fn download_segment(&self, pair: &str, mut segment: Segment, checkpoint: bool) -> Result<usize, PrefillError> {
+        let mut stored = 0;
+
+        loop {
+            println!("Downloading chunk from {} : {}", segment.start, segment.end);
+            let trades = self.with_retries(|| self.client.fetch_trade_history(pair, segment.start, segment.end))?;
+            let trade_count = trades.len();
+            let sampled = downsample(trades);
+            let observations: Vec<(NaiveDateTime, f32)> = sampled.iter().map(|trade| (trade.time, trade.rate)).collect();
+            self.store.insert_rates(pair, &observations).map_err(PrefillError::Other)?;
+            stored += sampled.len();
+
+            let more_remaining = segment.advance(trade_count, sampled.first().map(|trade| trade.time.timestamp()), self.page_size);
+            if checkpoint {
+                self.store.save_checkpoint(&Checkpoint::new(pair, &segment, !more_remaining)).map_err(PrefillError::Other)?;
+            }
+            if !more_remaining {
+                return Ok(stored);
+            }
+            // wait a few seconds before downloading the next chunk to avoid overloading their API
+            thread::sleep(self.delays.chunk);
+        }
+    }
+
+    /// Downloads historical fiat rates and the trade history for all pairs listed on Poloniex.  Returns the names of all
+    /// pairs for which the download failed.
+    pub fn run(&self) -> Result<Vec<String>, PrefillError> {
+        self.store.create_tables().map_err(PrefillError::Other)?;
+        if let Err(err) = self.download_fiat_rates() {
+            println!("Error while downloading historical fiat rates: {:?}", err);
+        }
+
+        let currencies = self.with_retries(|| self.client.fetch_currencies())?;
+        let now = Utc::now().timestamp();
+        let mut failed = Vec::new();
+
+        for currency in currencies {
+            let pair = pair_for_currency(&currency);
+            println!("Starting download for pair {}...", pair);
+
+            let res = self.store.register_pair(&pair)
+                .map_err(PrefillError::Other)
+                .and_then(|_| self.download_pair(&pair, now));
+            match res {
+                Ok(count) => println!("Finished downloading {}; stored {} trades.", pair, count),
+                // if no data is available for the pair, ignore it and move on
+                Err(PrefillError::InvalidPair) => println!("No trade history available for {}; skipping.", pair),
+                Err(err) => {
+                    println!("Error while downloading historical trade data for pair {}: {:?}", pair, err);
+                    failed.push(pair);
+                },
+            }
+
+            thread::sleep(self.delays.pair);
+        }
+
+        Ok(failed)
+    }
+}
+
+#[cfg(test)]
+struct MemoryStore(
+    ::std::sync::Mutex<HashMap<String, Vec<(NaiveDateTime, f32)>>>,
+    ::std::sync::Mutex<HashMap<String, Checkpoint>>
+);
+
+#[cfg(test)]
+impl MemoryStore {
+    fn new() -> MemoryStore {
+        MemoryStore(::std::sync::Mutex::new(HashMap::new()), ::std::sync::Mutex::new(HashMap::new()))
+    }
+}
+
+#[cfg(test)]
+impl TradeStore for MemoryStore {
+    fn create_tables(&self) -> Result<(), String> { Ok(()) }
+
+    fn register_pair(&self, pair: &str) -> Result<(), String> {
+        self.0.lock().unwrap().entry(String::from(pair)).or_insert_with(Vec::new);
+        Ok(())
+    }
+
+    fn latest_trade_time(&self, pair: &str) -> Result<Option<NaiveDateTime>, String> {
+        Ok(self.0.lock().unwrap().get(pair).and_then(|rates| rates.iter().map(|&(time, _)| time).max()))
+    }
+
+    fn insert_rates(&self, pair: &str, rates: &[(NaiveDateTime, f32)]) -> Result<(), String> {
+        let mut pairs = self.0.lock().unwrap();
+        let stored = pairs.entry(String::from(pair)).or_insert_with(Vec::new);
+        stored.retain(|&(time, _)| !rates.iter().any(|&(new_time, _)| new_time == time));
+        stored.extend_from_slice(rates);
+        stored.sort_by_key(|&(time, _)| time);
+        Ok(())
+    }
+
+    fn load_checkpoint(&self, pair: &str) -> Result<Option<Checkpoint>, String> {
+        Ok(self.1.lock().unwrap().get(pair).cloned())
+    }
+
+    fn save_checkpoint(&self, checkpoint: &Checkpoint) -> Result<(), String> {
+        self.1.lock().unwrap().insert(checkpoint.pair.clone(), checkpoint.clone());
+        Ok(())
+    }
+
+    fn list_checkpoints(&self) -> Result<Vec<Checkpoint>, String> {
+        let mut checkpoints: Vec<Checkpoint> = self.1.lock().unwrap().values().cloned().collect();
+        checkpoints.sort_by(|a, b| a.pair.cmp(&b.pair));
+        Ok(checkpoints)
+    }
+}
+
+/// Starts a HTTP server that mimics the `returnTradeHistory` endpoint of the Poloniex API, serving trades one minute apart
+/// starting at `first_trade`.  The first request made to it is answered with a rate limit error and requests for windows
+/// ending before `fail_before` are answered with an invalid response.
+#[cfg(test)]
+fn start_mock_poloniex(first_trade: i64, trade_count: i64, page_size: usize, fail_before: i64) -> ::hyper::server::Listening {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use hyper::server::{Request, Response, Server};
+    use hyper::uri::RequestUri;
+
+    let request_count = AtomicUsize::new(0);
+    Server::http("127.0.0.1:0").unwrap().handle(move |req: Request, res: Response| {
+        if request_count.fetch_add(1, Ordering::SeqCst) == 0 {
+            res.send(b"{\"error\":\"Too many requests. Please try again later.\"}").unwrap();
+            return;
+        }
+
+        let path = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path.clone(),
+            _ => String::new(),
+        };
+        let param = |name: &str| -> i64 {
+            path.split(|c| c == '?' || c == '&')
+                .filter_map(|kv| {
+                    let mut split = kv.split('=');
+                    match (split.next(), split.next()) {
+                        (Some(key), Some(val)) if key == name => val.parse().ok(),
+                        _ => None,
+                    }
+                })
+                .next()
+                .unwrap()
+        };
+        let (start, end) = (param("start"), param("end"));
+        if end < fail_before {
+            res.send(b"<html>502 Bad Gateway</html>").unwrap();
+            return;
+        }
+
+        // trades are returned newest first with the oldest ones truncated
+        let trades: Vec<String> = (0..trade_count)
+            .rev()
+            .map(|i| (i, first_trade + (i * 60)))
+            .filter(|&(_, timestamp)| timestamp >= start && timestamp <= end)
+            .take(page_size)
+            .map(|(i, timestamp)| format!(
+                "{{\"globalTradeID\":{},\"tradeID\":{},\"date\":\"{}\",\"type\":\"buy\",\"rate\":\"0.0{}\"}}",
+                1000 + i, i, NaiveDateTime::from_timestamp(timestamp, 0).format(MYSQL_DATE_FORMAT), 100 + i
+            ))
+            .collect();
+        res.send(format!("[{}]", trades.join(",")).as_bytes()).unwrap();
+    }).unwrap()
+}
+
+#[cfg(test)]
+fn test_prefiller(listening: &::hyper::server::Listening) -> Prefiller<MemoryStore> {
+    let client = PoloClient::new(&format!("http://{}/public", listening.socket), COINBASE_API_URL);
+    let mut prefiller = Prefiller::new(client, MemoryStore::new());
+    prefiller.page_size = 10;
+    prefiller.delays = Delays {
+        chunk: Duration::from_millis(0),
+        pair: Duration::from_millis(0),
+        rate_limit: Duration::from_millis(0),
+        retry: Duration::from_millis(0),
+    };
+    prefiller
+}
+
+#[test]
+fn test_downsampling() {
+    let trades: Vec<Trade> = (0..10)
+        .rev()
+        .map(|i| Trade { id: i, time: NaiveDateTime::from_timestamp(1483228800 + (i * 20), 0), rate: 1.0 })
+        .collect();
+    let sampled = downsample(trades);
+
+    assert_eq!(sampled.iter().map(|trade| trade.id).collect::<Vec<i64>>(), vec![0, 3, 6, 9]);
+}
+
+#[test]
+fn test_backtracking_download() {
+    // 2017-01-01 00:00:00
+    let first_trade = 1483228800;
+    let mut listening = start_mock_poloniex(first_trade, 35, 10, 0);
+    let prefiller = test_prefiller(&listening);
+
+    let stored = prefiller.download_pair("BTC_XMR", first_trade + 3600).unwrap();
+    listening.close().unwrap();
+
+    // the initial rate limit error should be retried rather than dropping the pair, and all trades should be retrieved
+    // despite the result being truncated.
+    assert_eq!(stored, 35);
+    let pairs = prefiller.store.0.lock().unwrap();
+    let times: Vec<i64> = pairs["BTC_XMR"].iter().map(|&(time, _)| time.timestamp()).collect();
+    assert_eq!(times, (0..35).map(|i| first_trade + (i * 60)).collect::<Vec<i64>>());
+}
+
+#[test]
+fn test_resume_from_checkpoint() {
+    let first_trade = 1483228800;
+
+    // fail partway through backtracking through the oversized segment.  A stored rate from the previous day makes the
+    // download start right before the first trade, so the first chunks succeed and are checkpointed before the failure.
+    let mut listening = start_mock_poloniex(first_trade, 35, 10, first_trade + (20 * 60));
+    let mut prefiller = test_prefiller(&listening);
+    let previous_day = NaiveDateTime::from_timestamp(first_trade - 86400, 0);
+    prefiller.store.insert_rates("BTC_XMR", &[(previous_day, 0.01)]).unwrap();
+    assert!(prefiller.download_pair("BTC_XMR", first_trade + 3600).is_err());
+    listening.close().unwrap();
+
+    let checkpoint = prefiller.store.load_checkpoint("BTC_XMR").unwrap().unwrap();
+    assert!(checkpoint.backtracking);
+    assert!(!checkpoint.complete);
+
+    // the resumed download should fill in the older trades rather than starting after the newest stored trade
+    let mut listening = start_mock_poloniex(first_trade, 35, 10, 0);
+    let resumed = test_prefiller(&listening);
+    prefiller.client = resumed.client;
+    assert_eq!(prefiller.download_pair("BTC_XMR", first_trade + 3600).unwrap(), 15);
+    listening.close().unwrap();
+
+    assert!(prefiller.store.load_checkpoint("BTC_XMR").unwrap().unwrap().complete);
+    assert_eq!(prefiller.store.0.lock().unwrap()["BTC_XMR"].len(), 35 + 1);
+}
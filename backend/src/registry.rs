@@ -0,0 +1,154 @@
+//! Server-side registry of the pairs for which rates are stored.  The registry is discovered from the rate store at startup
+//! and can be refreshed while the server is running, so newly listed currencies don't require a recompile.  Pairs supplied
+//! by users are resolved to stored pair IDs through the registry so that user-supplied strings are never used to build
+//! queries.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use diesel::expression::sql_literal::sql;
+use diesel::mysql::MysqlConnection;
+use diesel::types::{BigInt, Integer, Timestamp};
+
+use super::debug;
+use normalized::{list_pairs, PairInfo};
+use store::RateStore;
+
+/// The minimum amount of time between two refreshes of the registry, since each refresh scans the entire `rates` table.
+const MIN_REFRESH_INTERVAL_SECS: u64 = 60;
+
+/// Information about a pair for which rates are stored
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PairMetadata {
+    #[serde(skip_serializing)]
+    pub id: i32,
+    /// The pair formatted like "BTC/XMR" as it is accepted by the rate APIs
+    pub pair: String,
+    pub base: String,
+    pub quote: String,
+    pub first_observation: Option<NaiveDateTime>,
+    pub last_observation: Option<NaiveDateTime>,
+    pub observation_count: i64,
+}
+
+/// The response of the currency listing API
+#[derive(Serialize)]
+pub struct CurrencyListing {
+    pub currencies: Vec<String>,
+    pub pairs: Vec<PairMetadata>,
+}
+
+struct RegistryState {
+    pairs: HashMap<(String, String), PairMetadata>,
+    last_refresh: Instant,
+}
+
+pub struct PairRegistry(RwLock<RegistryState>);
+
+impl PairMetadata {
+    /// Creates the metadata of the given pair from the first and last times and the number of its stored observations, if
+    /// any are stored.
+    pub fn new(pair: &PairInfo, stats: Option<(NaiveDateTime, NaiveDateTime, i64)>) -> PairMetadata {
+        let (first, last, count) = match stats {
+            Some((first, last, count)) => (Some(first), Some(last), count),
+            None => (None, None, 0),
+        };
+
+        PairMetadata {
+            id: pair.id,
+            pair: format!("{}/{}", pair.base, pair.quote),
+            base: pair.base.clone(),
+            quote: pair.quote.clone(),
+            first_observation: first,
+            last_observation: last,
+            observation_count: count,
+        }
+    }
+}
+
+/// Loads all registered pairs along with statistics about their stored observations from MySQL.
+pub fn load_pair_metadata(conn: &MysqlConnection) -> Result<Vec<PairMetadata>, String> {
+    let stats: HashMap<i32, (NaiveDateTime, NaiveDateTime, i64)> = sql::<(Integer, Timestamp, Timestamp, BigInt)>(
+        "SELECT pair_id, MIN(trade_time), MAX(trade_time), COUNT(*) FROM rates GROUP BY pair_id"
+    ).load::<(i32, NaiveDateTime, NaiveDateTime, i64)>(conn)
+        .map_err(debug)?
+        .into_iter()
+        .map(|(pair_id, first, last, count)| (pair_id, (first, last, count)))
+        .collect();
+
+    Ok(list_pairs(conn)?.iter().map(|pair| PairMetadata::new(pair, stats.get(&pair.id).cloned())).collect())
+}
+
+/// Loads all pairs listed by the store, keyed by their base and quote currencies.
+fn load_pairs(store: &RateStore) -> Result<HashMap<(String, String), PairMetadata>, String> {
+    Ok(store.list_pairs()?
+        .into_iter()
+        .map(|pair| ((pair.base.clone(), pair.quote.clone()), pair))
+        .collect())
+}
+
+impl PairRegistry {
+    pub fn load(store: &RateStore) -> Result<PairRegistry, String> {
+        Ok(PairRegistry(RwLock::new(RegistryState {
+            pairs: load_pairs(store)?,
+            last_refresh: Instant::now(),
+        })))
+    }
+
+    /// Reloads the registry from the store.  Returns `false` without reloading anything if the registry was refreshed too
+    /// recently.
+    pub fn refresh(&self, store: &RateStore) -> Result<bool, String> {
+        if self.0.read().unwrap().last_refresh.elapsed() < Duration::from_secs(MIN_REFRESH_INTERVAL_SECS) {
+            return Ok(false);
+        }
+
+        let pairs = load_pairs(store)?;
+        let mut state = self.0.write().unwrap();
+        state.pairs = pairs;
+        state.last_refresh = Instant::now();
+        Ok(true)
+    }
+
+    /// Returns the ID of the stored pair with the given base and quote currencies.
+    pub fn lookup(&self, base: &str, quote: &str) -> Option<i32> {
+        self.0.read().unwrap().pairs.get(&(String::from(base), String::from(quote))).map(|pair| pair.id)
+    }
+
+    /// Returns all registered currencies and pairs, sorted by name.
+    pub fn listing(&self) -> CurrencyListing {
+        let state = self.0.read().unwrap();
+        let mut pairs: Vec<PairMetadata> = state.pairs.values().cloned().collect();
+        pairs.sort_by(|a, b| a.pair.cmp(&b.pair));
+        let currencies: BTreeSet<String> = pairs.iter()
+            .flat_map(|pair| vec![pair.base.clone(), pair.quote.clone()])
+            .collect();
+
+        CurrencyListing {
+            currencies: currencies.into_iter().collect(),
+            pairs: pairs,
+        }
+    }
+}
+
+#[test]
+fn test_registry_lookup() {
+    let mut pairs = HashMap::new();
+    pairs.insert((String::from("BTC"), String::from("XMR")), PairMetadata {
+        id: 7,
+        pair: String::from("BTC/XMR"),
+        base: String::from("BTC"),
+        quote: String::from("XMR"),
+        first_observation: None,
+        last_observation: None,
+        observation_count: 0,
+    });
+    let registry = PairRegistry(RwLock::new(RegistryState { pairs: pairs, last_refresh: Instant::now() }));
+
+    assert_eq!(registry.lookup("BTC", "XMR"), Some(7));
+    assert_eq!(registry.lookup("XMR", "BTC"), None);
+    assert_eq!(registry.lookup("BTC", "XMR'; DROP TABLE rates; --"), None);
+    assert_eq!(registry.listing().currencies, vec![String::from("BTC"), String::from("XMR")]);
+}
@@ -1,39 +1,177 @@
 //! Sets up the API endpoints that are exposed via the Rocket webserver to the clients.
 
-use std::io::Read;
+use std::cell::Cell;
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufReader, Read};
+use std::mem;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
 
-use chrono::NaiveDateTime;
+use chrono::{Duration, NaiveDateTime};
+use rayon;
 use rayon::prelude::*;
-use rocket::{Data, Request, Response, State};
-use rocket::http::Status;
+use rocket::{Data, Request, State};
+use rocket::http::{ContentType, Status};
 use rocket::data::{self, FromData};
+use rocket::request::{self, FormItems, FromRequest};
+use rocket::response::{self, Responder, Response, Stream};
 use rocket::Outcome::*;
 use rocket_contrib::Json;
-use serde_json;
+use serde::Serialize;
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde_json::{self, Value};
 
-use super::{debug, DbPool, RateCache, MYSQL_DATE_FORMAT};
-use db_query::get_rate;
+use super::{debug, DbPool, RateCache};
+use cache::RateCacheStats;
+use config::{AdminConfig, FeedbackConfig, LimitsConfig};
+use candles::{self, Candle};
+use db_query::{get_rate, search_radius, stored_legs, HistRateQueryResult, RateLeg, RateMode, RateOptions};
+use error::{ApiError, ErrorBody, ErrorEnvelope};
 use feedback::deliver_feedback;
+use normalized::split_pair_name;
+use registry::{CurrencyListing, PairRegistry};
+use store::{PrefetchedStore, RateStore};
+use timestamp;
 
-#[derive(Serialize, Deserialize)]
+/// The largest number of points that can be requested from the series API at once
+const MAX_SERIES_POINTS: i64 = 1000;
+/// The largest number of candles that can be requested from the candle API at once
+const MAX_CANDLES: i64 = 5000;
+/// The latest timestamp that candles can be requested for, which is the last second of the year 9999
+const MAX_CANDLE_TIMESTAMP: i64 = 253402300799;
+/// The longest span of time for which the observations of a pair are loaded at once while resolving a batch of rates
+const MAX_PREFETCH_DAYS: i64 = 30;
+/// The longest gap between the windows of observations needed by two rates of a batch that is loaded along with them
+/// rather than splitting them into separate queries
+const MAX_PREFETCH_GAP_HOURS: i64 = 24;
+/// The number of requests of a batch that are resolved together as soon as they've been parsed, while the rest of the
+/// batch is still being read
+const BATCH_CHUNK_SIZE: usize = 1000;
+/// The number of lines of a streamed batch that may be waiting for the client before resolving the batch is paused
+const STREAM_BUFFER_LINES: usize = 256;
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct RateResponse {
     pub pair: String,
     pub rate: Option<f32>,
     pub no_data: bool,
     pub cached: bool,
-    pub date: NaiveDateTime,
+    /// The requested timestamp, which is only missing if a batch request supplied one that couldn't be parsed
+    #[serde(with = "::timestamp::utc_option")]
+    pub date: Option<NaiveDateTime>,
+    /// The timestamp for which the rate was actually resolved, which is the start of the bucket containing `date` if
+    /// timestamps were bucketed and `date` itself otherwise
+    #[serde(with = "::timestamp::utc_option")]
+    pub resolved_date: Option<NaiveDateTime>,
+    /// The size of the bucket in seconds if timestamps were bucketed
+    pub bucket_seconds: Option<i64>,
+    /// The strategy that was used to resolve the rate from the stored observations
+    pub mode: RateMode,
+    /// The stored observations that were used to compute the rate.  Pairs that aren't stored directly are computed from
+    /// the rates of both of their currencies to BTC.
+    pub legs: Vec<RateLeg>,
+    /// The number of seconds between the oldest and newest of the observations in `legs`
+    pub leg_skew_seconds: Option<i64>,
+    /// The time of the observation in `legs` that is farthest from the requested timestamp
+    #[serde(with = "::timestamp::utc_option")]
+    pub matched_time: Option<NaiveDateTime>,
+    /// The number of seconds between `matched_time` and the requested timestamp, negative if the observation was made
+    /// before it.  Large distances indicate that the rate may not reflect the market at the requested time.
+    pub distance_seconds: Option<i64>,
+    /// Why no rate was returned, distinguishing pairs that aren't available, timestamps without nearby observations,
+    /// and failures of the rate store
+    pub error: Option<ErrorBody>,
+}
+
+impl RateResponse {
+    fn new(
+        pair: String, timestamp: NaiveDateTime, options: RateOptions, query_result: Option<HistRateQueryResult>,
+        cached: bool
+    ) -> RateResponse {
+        match query_result {
+            Some(qr) => {
+                let matched = qr.farthest_observation(timestamp);
+                RateResponse {
+                    pair: pair,
+                    rate: Some(qr.rate),
+                    no_data: false,
+                    cached: cached,
+                    date: Some(timestamp),
+                    resolved_date: Some(options.bucket_timestamp(timestamp)),
+                    bucket_seconds: options.bucket,
+                    mode: options.mode,
+                    leg_skew_seconds: Some(qr.leg_skew_seconds()),
+                    legs: qr.legs,
+                    matched_time: matched.map(|(time, _)| time),
+                    distance_seconds: matched.map(|(_, distance)| distance),
+                    error: None,
+                }
+            },
+            None => RateResponse {
+                cached: cached,
+                ..RateResponse::failed(pair, Some(timestamp), options, ApiError::NoData)
+            },
+        }
+    }
+
+    /// Creates the response for a rate that couldn't be resolved because of the supplied error.
+    fn failed(pair: String, timestamp: Option<NaiveDateTime>, options: RateOptions, err: ApiError) -> RateResponse {
+        RateResponse {
+            pair: pair,
+            rate: None,
+            no_data: true,
+            cached: false,
+            date: timestamp,
+            resolved_date: timestamp.map(|timestamp| options.bucket_timestamp(timestamp)),
+            bucket_seconds: options.bucket,
+            mode: options.mode,
+            legs: Vec::new(),
+            leg_skew_seconds: None,
+            matched_time: None,
+            distance_seconds: None,
+            error: Some(err.body()),
+        }
+    }
 }
 
 #[derive(Deserialize)]
 pub struct RateRequest {
     pub date: NaiveDateTime,
     pub pair: String,
+    pub mode: Option<RateMode>,
+    pub max_distance: Option<i64>,
+    pub bucket: Option<i64>,
+}
+
+impl RateRequest {
+    /// Returns the options for this rate, falling back to the supplied options for any that aren't set.
+    pub fn options(&self, defaults: RateOptions) -> RateOptions {
+        RateOptions {
+            mode: self.mode.unwrap_or(defaults.mode),
+            max_distance: self.max_distance.or(defaults.max_distance),
+            bucket: self.bucket.or(defaults.bucket),
+        }
+    }
 }
 
 #[derive(Deserialize)]
 pub struct RawRateRequest {
-    pub date: String,
+    /// The requested timestamp as a Unix timestamp in seconds or milliseconds, either as a number or a string, or as a
+    /// string in any of the other formats accepted by `timestamp::parse`
+    pub date: Value,
     pub pair: String,
+    /// Overrides the mode supplied in the query string of the batch request for this rate
+    #[serde(default)]
+    pub mode: Option<RateMode>,
+    /// Overrides the maximum distance supplied in the query string of the batch request for this rate
+    #[serde(default)]
+    pub max_distance: Option<i64>,
+    /// Overrides the bucket size supplied in the query string of the batch request for this rate
+    #[serde(default)]
+    pub bucket: Option<i64>,
 }
 
 #[derive(Deserialize)]
@@ -48,180 +186,851 @@ pub struct FeedbackResponse {
 }
 
 impl RawRateRequest {
-    pub fn to_rate_request(self) -> Result<RateRequest, String> {
+    pub fn to_rate_request(self) -> Result<RateRequest, ApiError> {
         Ok(RateRequest {
-            date: NaiveDateTime::parse_from_str(&self.date, MYSQL_DATE_FORMAT)
-                .map_err(|err| format!("Error parsing `NaiveDateTime` from String: {:?}", err))?,
+            date: timestamp::parse_value(&self.date).map_err(ApiError::InvalidRequest)?,
             pair: self.pair,
+            mode: self.mode,
+            max_distance: match self.max_distance {
+                Some(max_distance) => {
+                    Some(RateOptions::validate_max_distance(max_distance).map_err(ApiError::InvalidRequest)?)
+                },
+                None => None,
+            },
+            bucket: match self.bucket {
+                Some(bucket) => Some(RateOptions::validate_bucket(bucket).map_err(ApiError::InvalidRequest)?),
+                None => None,
+            },
         })
     }
 }
 
-pub struct BatchRateRequest(Vec<RateRequest>);
+/// Reads the rate resolution options from the query string, for example `?mode=previous&max_distance=3600&bucket=60`.
+/// Options that aren't supplied fall back to their defaults.
+impl<'a, 'r> FromRequest<'a, 'r> for RateOptions {
+    type Error = String;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, String> {
+        let mut options = RateOptions::default();
+        let query = match request.uri().query() {
+            Some(query) => query,
+            None => { return Success(options); },
+        };
+
+        for (key, value) in FormItems::from(query) {
+            let value = match value.url_decode() {
+                Ok(value) => value,
+                Err(err) => { return Failure((Status::BadRequest, debug(err))); },
+            };
+
+            match key.as_str() {
+                "mode" => match RateMode::parse(&value) {
+                    Some(mode) => { options.mode = mode; },
+                    None => { return Failure((Status::BadRequest, format!("Invalid rate mode supplied: {}", value))); },
+                },
+                "max_distance" => match value.parse::<i64>().map_err(debug).and_then(RateOptions::validate_max_distance) {
+                    Ok(max_distance) => { options.max_distance = Some(max_distance); },
+                    Err(err) => { return Failure((Status::BadRequest, err)); },
+                },
+                "bucket" => match value.parse::<i64>().map_err(debug).and_then(RateOptions::validate_bucket) {
+                    Ok(bucket) => { options.bucket = Some(bucket); },
+                    Err(err) => { return Failure((Status::BadRequest, err)); },
+                },
+                _ => (),
+            }
+        }
+
+        Success(options)
+    }
+}
+
+/// A regularly sampled range of timestamps for which rates are requested from the series API
+#[derive(Debug, PartialEq)]
+pub struct SeriesRequest {
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+    /// The number of seconds between two points of the series
+    pub step: i64,
+}
+
+impl SeriesRequest {
+    /// Parses the `start`, `end`, and `step` parameters out of a query string and checks that the series doesn't contain
+    /// more than `MAX_SERIES_POINTS` points.
+    fn parse(query: &str) -> Result<SeriesRequest, String> {
+        let (mut start, mut end, mut step) = (None, None, None);
+        for (key, value) in FormItems::from(query) {
+            let value = value.url_decode().map_err(debug)?;
+            match key.as_str() {
+                "start" => { start = Some(timestamp::parse(&value)?); },
+                "end" => { end = Some(timestamp::parse(&value)?); },
+                "step" => { step = Some(value.parse::<i64>().map_err(debug)?); },
+                _ => (),
+            }
+        }
+
+        let (start, end, step) = match (start, end, step) {
+            (Some(start), Some(end), Some(step)) => (start, end, step),
+            _ => { return Err(String::from("The `start`, `end`, and `step` parameters are required.")); },
+        };
+        if step <= 0 {
+            return Err(String::from("The step must be a positive number of seconds."));
+        }
+        if end < start {
+            return Err(String::from("The end of the series must not be before its start."));
+        }
+        let point_count = (end.signed_duration_since(start).num_seconds() / step) + 1;
+        if point_count > MAX_SERIES_POINTS {
+            return Err(format!("The series contains {} points, but at most {} are allowed.", point_count, MAX_SERIES_POINTS));
+        }
+
+        Ok(SeriesRequest { start: start, end: end, step: step })
+    }
+
+    /// Returns the timestamps of all points in the series, starting at `start` and ending at or before `end`.
+    pub fn timestamps(&self) -> Vec<NaiveDateTime> {
+        let point_count = (self.end.signed_duration_since(self.start).num_seconds() / self.step) + 1;
+        (0..point_count).map(|i| self.start + Duration::seconds(i * self.step)).collect()
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for SeriesRequest {
+    type Error = String;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, String> {
+        match SeriesRequest::parse(request.uri().query().unwrap_or("")) {
+            Ok(series) => Success(series),
+            Err(err) => Failure((Status::BadRequest, err)),
+        }
+    }
+}
+
+/// A range of candles requested from the candle API.  Like the Poloniex chart API, `start` and `end` are Unix timestamps
+/// in seconds and `period` is the length of each candle in seconds.
+#[derive(Debug, PartialEq)]
+pub struct CandleRequest {
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+    pub period: i64,
+}
+
+/// Parses the timestamp of one end of a candle range in seconds.  The frontend computes timestamps from milliseconds, so
+/// they may contain a fractional part, which is dropped.
+fn parse_candle_timestamp(value: &str) -> Result<i64, String> {
+    let seconds = value.parse::<f64>().map_err(debug)?;
+    // checked before casting since values that don't fit into an `i64` can't be cast
+    if !seconds.is_finite() || seconds < 0. || seconds > MAX_CANDLE_TIMESTAMP as f64 {
+        return Err(format!("Invalid timestamp supplied: {}", value));
+    }
 
-impl FromData for BatchRateRequest {
+    Ok(seconds as i64)
+}
+
+impl CandleRequest {
+    /// Parses the `start`, `end`, and `period` parameters out of a query string and checks that the range doesn't contain
+    /// more than `MAX_CANDLES` candles.
+    fn parse(query: &str) -> Result<CandleRequest, String> {
+        let (mut start, mut end, mut period) = (None, None, None);
+        for (key, value) in FormItems::from(query) {
+            let value = value.url_decode().map_err(debug)?;
+            match key.as_str() {
+                "start" => { start = Some(parse_candle_timestamp(&value)?); },
+                "end" => { end = Some(parse_candle_timestamp(&value)?); },
+                "period" => { period = Some(value.parse::<i64>().map_err(debug)?); },
+                _ => (),
+            }
+        }
+
+        let (start, end, period) = match (start, end, period) {
+            (Some(start), Some(end), Some(period)) => (start, end, period),
+            _ => { return Err(String::from("The `start`, `end`, and `period` parameters are required.")); },
+        };
+        if candles::source_period(period).is_none() {
+            return Err(format!("Candles aren't available for a period of {} seconds.", period));
+        }
+        if end < start {
+            return Err(String::from("The end of the range must not be before its start."));
+        }
+        let candle_count = ((end - start) / period) + 1;
+        if candle_count > MAX_CANDLES {
+            return Err(format!("The range contains {} candles, but at most {} are allowed.", candle_count, MAX_CANDLES));
+        }
+
+        Ok(CandleRequest {
+            start: NaiveDateTime::from_timestamp(start, 0),
+            end: NaiveDateTime::from_timestamp(end, 0),
+            period: period,
+        })
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for CandleRequest {
     type Error = String;
 
-    fn from_data(_: &Request, data: Data) -> data::Outcome<Self, String> {
-        // Read the data into a String.
-        let mut s = String::new();
-        if let Err(err) = data.open().read_to_string(&mut s) {
-            println!("Error reading data into String: {:?}", err);
-            return Failure((Status::InternalServerError, format!("Error reading data into String: {:?}", err)));
-        }
-
-        match serde_json::from_str::<Vec<RawRateRequest>>(&s) {
-            Ok(raw_requests) => {
-                let mut mapped = Vec::with_capacity(raw_requests.len());
-                for raw_req in raw_requests {
-                    let req = match raw_req.to_rate_request() {
-                        Ok(r) => r,
-                        Err(err) => {
-                            return Failure((Status::InternalServerError, err));
-                        }
-                    };
-                    mapped.push(req);
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, String> {
+        match CandleRequest::parse(request.uri().query().unwrap_or("")) {
+            Ok(candles) => Success(candles),
+            Err(err) => Failure((Status::BadRequest, err)),
+        }
+    }
+}
+
+/// The rates requested from the batch rate API.  Requests that couldn't be parsed are kept along with the pair that
+/// they were made for so that they can be answered with an error without failing the whole batch.
+pub struct BatchRateRequest(Vec<Result<RateRequest, (String, ApiError)>>);
+
+/// Parses a single request of a batch, which is supplied as an arbitrary JSON value.
+fn parse_batch_item(value: Value) -> Result<RateRequest, (String, ApiError)> {
+    let pair = String::from(value.get("pair").and_then(|pair| pair.as_str()).unwrap_or(""));
+
+    serde_json::from_value::<RawRateRequest>(value)
+        .map_err(|err| ApiError::InvalidRequest(format!("Invalid rate request: {}", err)))
+        .and_then(RawRateRequest::to_rate_request)
+        .map_err(|err| (pair, err))
+}
+
+/// Reads at most `remaining` bytes from the inner reader and fails, recording that the limit was `exceeded`, if the
+/// inner reader holds more than that.
+struct LimitedReader<R> {
+    inner: R,
+    remaining: u64,
+    exceeded: bool,
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            // the limit only counts as exceeded if there's actually anything left to read
+            let mut byte = [0u8; 1];
+            if self.inner.read(&mut byte)? == 0 {
+                return Ok(0);
+            }
+            self.exceeded = true;
+            return Err(io::Error::new(io::ErrorKind::Other, "The request body is too large."));
+        }
+
+        let max_len = cmp::min(buf.len() as u64, self.remaining) as usize;
+        let read = self.inner.read(&mut buf[..max_len])?;
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Deserializes the array of a batch request one rate at a time and hands the parsed requests to `on_chunk` in chunks of
+/// `BATCH_CHUNK_SIZE`, along with the position of the first of them in the batch, so that neither the body nor the parsed
+/// batch ever has to be held in memory as a whole.  Fails as soon as the array turns out to hold more than `max_size`
+/// rates, recording that in `too_many`, or once `on_chunk` returns `false`.
+struct BatchVisitor<'a, F: 'a> {
+    max_size: usize,
+    too_many: &'a Cell<bool>,
+    on_chunk: &'a mut F,
+}
+
+impl<'a, 'de, F: FnMut(usize, BatchRateRequest) -> bool> Visitor<'de> for BatchVisitor<'a, F> {
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array of rate requests")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let (mut offset, mut chunk) = (0, Vec::new());
+        loop {
+            let item = seq.next_element::<Value>()?;
+            if let Some(item) = item {
+                if offset + chunk.len() == self.max_size {
+                    self.too_many.set(true);
+                    return Err(de::Error::custom("too many rates were requested"));
                 }
-                Success(BatchRateRequest(mapped))
-            },
-            Err(err) => {
-                println!("Error parsing data out of JSON: {:?}", err);
-                Failure((Status::InternalServerError, format!("Error parsing data out of JSON: {:?}", err)))
-            },
+                chunk.push(parse_batch_item(item));
+                if chunk.len() < BATCH_CHUNK_SIZE {
+                    continue;
+                }
+            } else if chunk.is_empty() {
+                return Ok(offset);
+            }
+
+            let len = chunk.len();
+            if !(self.on_chunk)(offset, BatchRateRequest(mem::replace(&mut chunk, Vec::new()))) {
+                return Err(de::Error::custom("the batch was abandoned"));
+            }
+            offset += len;
         }
     }
 }
 
-/// Fetches the value for a historical exchange rate.  First attempts to read it from the cache.  If not in the cache,
-/// makes a query to the database and inserts the response into the cache.
-fn retrieve_hist_rate(db_pool: &DbPool, rate_cache: &RateCache, pair: String, timestamp: NaiveDateTime) -> RateResponse {
-    // attempt to fetch the value from the rate cache and, if it is found, return it without making any DB queries
-    match rate_cache.get(pair.clone(), timestamp) {
-        Some(rate) => {
-            return RateResponse {
-                pair: pair.clone(),
-                rate: rate,
-                no_data: rate.is_none(),
-                cached: true,
-                date: timestamp,
-            };
+/// Parses the JSON array of a batch request while it's read from `body`, enforcing the supplied limits, and hands each
+/// chunk of parsed requests to `on_chunk` as described for `BatchVisitor`.  Returns the number of requests in the batch.
+fn read_batch<R: Read, F>(body: R, limits: &LimitsConfig, mut on_chunk: F) -> Result<usize, ApiError>
+    where F: FnMut(usize, BatchRateRequest) -> bool
+{
+    let mut limited = LimitedReader { inner: body, remaining: limits.max_body_bytes, exceeded: false };
+    let too_many = Cell::new(false);
+    let parsed = {
+        let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(&mut limited));
+        let visitor = BatchVisitor { max_size: limits.max_batch_size, too_many: &too_many, on_chunk: &mut on_chunk };
+        match (&mut deserializer).deserialize_seq(visitor) {
+            Ok(count) => deserializer.end().map(|()| count),
+            Err(err) => Err(err),
+        }
+    };
+
+    match parsed {
+        Ok(count) => Ok(count),
+        Err(_) if limited.exceeded => Err(ApiError::PayloadTooLarge(
+            format!("The request body must not be larger than {} bytes.", limits.max_body_bytes)
+        )),
+        Err(_) if too_many.get() => Err(ApiError::BatchTooLarge(limits.max_batch_size)),
+        Err(err) => {
+            println!("Error parsing data out of JSON: {:?}", err);
+            Err(ApiError::InvalidRequest(format!("The body must be a JSON array: {}", err)))
         },
-        None => (),
     }
+}
 
-    // perform the database query for the historical rate and return the result
-    let db_conn = &*db_pool.get_conn();
-    let query_result = get_rate(&pair, timestamp, db_conn);
+/// The body of a batch request, which is only parsed while its rates are being resolved
+pub struct BatchBody {
+    data: Data,
+    limits: LimitsConfig,
+}
 
-    // since we didn't find the value in the cache, insert the current one if it was recorded over an hour.
-    if query_result.is_ok() {
-        let res_inner = *query_result.as_ref().unwrap();
+impl BatchBody {
+    /// Reads the batch, handing each chunk of requests to `on_chunk` as soon as it has been parsed.  Returns the number of
+    /// requests in the batch.
+    fn read_chunks<F>(self, on_chunk: F) -> Result<usize, ApiError> where F: FnMut(usize, BatchRateRequest) -> bool {
+        read_batch(self.data.open(), &self.limits, on_chunk)
+    }
+}
+
+impl FromData for BatchBody {
+    type Error = ApiError;
 
-        // only cache results older than the last 60 minutes
-        if res_inner.is_none() || res_inner.as_ref().unwrap().1 > 60 {
-            rate_cache.set(pair.clone(), res_inner, timestamp);
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, ApiError> {
+        let limits = match request.guard::<State<LimitsConfig>>() {
+            Success(limits) => limits.inner().clone(),
+            _ => LimitsConfig::default(),
+        };
+
+        // bodies that announce that they're too large are rejected without reading them
+        let length = request.headers().get_one("Content-Length").and_then(|length| length.parse::<u64>().ok());
+        if length.map(|length| length > limits.max_body_bytes).unwrap_or(false) {
+            let err = ApiError::PayloadTooLarge(
+                format!("The request body must not be larger than {} bytes.", limits.max_body_bytes)
+            );
+            return Failure((err.status(), err));
         }
-    }
 
-    if query_result.is_err() {
-        println!("{:?}", query_result);
+        Success(BatchBody { data: data, limits: limits })
     }
-    match query_result {
-        Ok(Some(qr)) => RateResponse{
-            pair: pair,
-            rate: Some(qr.0),
-            no_data: false,
-            cached: false,
-            date: timestamp,
+}
+
+/// Returns the response for a historical exchange rate from the cache if it has been cached.  If timestamps are bucketed,
+/// the rate cached for the start of the bucket containing the timestamp is used.
+fn cached_hist_rate(
+    rate_cache: &RateCache, pair: &str, timestamp: NaiveDateTime, options: RateOptions
+) -> Option<RateResponse> {
+    // the bucket has already been applied, so leaving it out of the cache key lets unbucketed requests for the start of
+    // the bucket share the cached rate
+    let resolved_timestamp = options.bucket_timestamp(timestamp);
+    let resolution_options = RateOptions { bucket: None, ..options };
+
+    rate_cache.get(String::from(pair), resolved_timestamp, resolution_options)
+        .map(|query_result| RateResponse::new(String::from(pair), timestamp, options, query_result, true))
+}
+
+/// Resolves a historical exchange rate from the rate store without consulting the cache and inserts the result into the
+/// cache.  Returns an error if the pair isn't available or the rate store failed; timestamps without nearby observations
+/// result in a response without a rate.
+fn resolve_hist_rate(
+    registry: &PairRegistry, rate_store: &RateStore, rate_cache: &RateCache, pair: String, timestamp: NaiveDateTime,
+    options: RateOptions
+) -> Result<RateResponse, ApiError> {
+    let resolved_timestamp = options.bucket_timestamp(timestamp);
+    let resolution_options = RateOptions { bucket: None, ..options };
+
+    // query the rate store for the historical rate and return the result
+    let query_result = match get_rate(&pair, resolved_timestamp, resolution_options, registry, rate_store) {
+        Ok(query_result) => query_result,
+        Err(err) => {
+            println!("Error while resolving the rate of {} at {}: {:?}", pair, resolved_timestamp, err);
+            return Err(err);
         },
-        Ok(None) | Err(_) => RateResponse{
-            pair: pair,
-            rate: None,
-            no_data: true,
-            cached: false,
-            date: timestamp,
+    };
+
+    // since we didn't find the value in the cache, insert the current one if it was recorded over an hour.
+    // only cache results older than the last 60 minutes
+    if query_result.is_none() || query_result.as_ref().unwrap().minutes_ago > 60 {
+        rate_cache.set(pair.clone(), query_result.clone(), resolved_timestamp, resolution_options);
+    }
+
+    Ok(RateResponse::new(pair, timestamp, options, query_result, false))
+}
+
+/// Fetches the value for a historical exchange rate.  First attempts to read it from the cache.  If not in the cache,
+/// resolves it from the rate store and inserts the response into the cache.  If timestamps are bucketed, the rate is
+/// resolved and cached for the start of the bucket containing the timestamp.
+fn retrieve_hist_rate(
+    registry: &PairRegistry, rate_store: &RateStore, rate_cache: &RateCache, pair: String, timestamp: NaiveDateTime,
+    options: RateOptions
+) -> Result<RateResponse, ApiError> {
+    // attempt to fetch the value from the rate cache and, if it is found, return it without making any DB queries
+    match cached_hist_rate(rate_cache, &pair, timestamp, options) {
+        Some(response) => Ok(response),
+        None => resolve_hist_rate(registry, rate_store, rate_cache, pair, timestamp, options),
+    }
+}
+
+/// A unique rate requested in a batch: the pair, the requested timestamp, and the options used to resolve it
+type BatchKey = (String, NaiveDateTime, RateOptions);
+
+/// Requests of a batch for the same pair whose rates are resolved from observations that are loaded together
+struct RequestCluster {
+    /// The IDs of the stored pairs whose observations are needed to resolve the rates
+    pair_ids: Vec<i32>,
+    /// The window of observations needed to resolve all of the rates, or `None` if they're resolved from the store
+    /// directly
+    window: Option<(NaiveDateTime, NaiveDateTime)>,
+    /// The indices of the requests in the de-duplicated batch
+    requests: Vec<usize>,
+}
+
+/// Splits the requests of a batch for a single pair into clusters of nearby timestamps.  A cluster is extended as long
+/// as the gap to the window of the next request is at most `MAX_PREFETCH_GAP_HOURS` and the cluster spans no more than
+/// `MAX_PREFETCH_DAYS`, which keeps the number of observations loaded for a cluster bounded.
+fn cluster_requests(indices: Vec<usize>, requests: &[BatchKey], registry: &PairRegistry) -> Vec<RequestCluster> {
+    let legs = match stored_legs(&requests[indices[0]].0, registry) {
+        Ok(legs) => legs,
+        Err(_) => Vec::new(),
+    };
+    if legs.is_empty() {
+        // the rates either can't be resolved at all or don't need any observations
+        return vec![RequestCluster { pair_ids: Vec::new(), window: None, requests: indices }];
+    }
+    let pair_ids: Vec<i32> = legs.iter().map(|&(pair_id, _)| pair_id).collect();
+
+    let mut windows: Vec<(Option<(NaiveDateTime, NaiveDateTime)>, usize)> = indices.into_iter()
+        .map(|i| {
+            let (_, date, options) = requests[i];
+            let timestamp = options.bucket_timestamp(date);
+            let radius = legs.iter().map(|&(_, quote)| search_radius(quote, options)).max().unwrap();
+            match (timestamp.checked_sub_signed(radius), timestamp.checked_add_signed(radius)) {
+                (Some(start), Some(end)) => (Some((start, end)), i),
+                _ => (None, i),
+            }
+        })
+        .collect();
+    windows.sort();
+
+    let mut clusters: Vec<RequestCluster> = Vec::new();
+    for (window, i) in windows {
+        if let (Some(cluster), Some((window_start, window_end))) = (clusters.last_mut(), window) {
+            if let Some((start, end)) = cluster.window {
+                if window_start.signed_duration_since(end) <= Duration::hours(MAX_PREFETCH_GAP_HOURS) &&
+                    window_end.signed_duration_since(start) <= Duration::days(MAX_PREFETCH_DAYS)
+                {
+                    cluster.window = Some((start, if window_end > end { window_end } else { end }));
+                    cluster.requests.push(i);
+                    continue;
+                }
+            }
+        }
+        clusters.push(RequestCluster { pair_ids: pair_ids.clone(), window: window, requests: vec![i] });
+    }
+
+    clusters
+}
+
+/// Resolves the rates of a cluster after loading the observations in its window with a single range query for each of
+/// the stored pairs that they're computed from.
+fn resolve_cluster(
+    registry: &PairRegistry, rate_store: &RateStore, rate_cache: &RateCache, requests: &[BatchKey],
+    cluster: &RequestCluster
+) -> Vec<(usize, Result<RateResponse, ApiError>)> {
+    let prefetched = match cluster.window {
+        Some((start, end)) => match PrefetchedStore::load(rate_store, &cluster.pair_ids, start, end) {
+            Ok(prefetched) => Some(prefetched),
+            Err(err) => {
+                // the rates are resolved one by one instead, which reports the failure for each of them
+                println!("Error while loading observations for a batch of rates: {}", err);
+                None
+            },
         },
+        None => None,
+    };
+    let store: &RateStore = match prefetched {
+        Some(ref prefetched) => prefetched,
+        None => rate_store,
+    };
+
+    cluster.requests.iter()
+        .map(|&i| {
+            let (ref pair, date, options) = requests[i];
+            (i, resolve_hist_rate(registry, store, rate_cache, pair.clone(), date, options))
+        })
+        .collect()
+}
+
+/// Resolves the rates of a batch request, calling `emit` with the position of each request in the batch and its response
+/// as soon as the response is known.  Once `emit` returns `false`, no further rates are resolved.  Identical requests are
+/// only resolved once.  Rates that aren't cached are grouped
+/// by pair and resolved from observations that are loaded for each cluster of nearby timestamps at once rather than with
+/// separate queries for every rate.
+fn resolve_batch_with<F: Fn(usize, RateResponse) -> bool + Sync>(
+    registry: &PairRegistry, rate_store: &RateStore, rate_cache: &RateCache, requests: &BatchRateRequest,
+    default_options: RateOptions, emit: F
+) {
+    let stopped = AtomicBool::new(false);
+    let emit = |index: usize, response: RateResponse| {
+        if !emit(index, response) {
+            stopped.store(true, Ordering::Relaxed);
+        }
+    };
+
+    // collapse identical requests, remembering the positions in the batch that each of them was made at
+    let mut unique: Vec<BatchKey> = Vec::new();
+    let mut positions: HashMap<BatchKey, usize> = HashMap::new();
+    let mut slots: Vec<Vec<usize>> = Vec::new();
+    for (index, req) in requests.0.iter().enumerate() {
+        match *req {
+            Ok(ref req) => {
+                let key = (req.pair.clone(), req.date, req.options(default_options));
+                if !positions.contains_key(&key) {
+                    positions.insert(key.clone(), unique.len());
+                    unique.push(key.clone());
+                    slots.push(Vec::new());
+                }
+                slots[positions[&key]].push(index);
+            },
+            Err((ref pair, ref err)) => {
+                emit(index, RateResponse::failed(pair.clone(), None, default_options, err.clone()));
+            },
+        }
+    }
+    let emit_unique = |i: usize, response: Result<RateResponse, ApiError>| {
+        let (ref pair, date, options) = unique[i];
+        let response = response.unwrap_or_else(|err| RateResponse::failed(pair.clone(), Some(date), options, err));
+        for &index in &slots[i] {
+            emit(index, response.clone());
+        }
+    };
+
+    // answer cached rates right away and group the others by pair
+    let mut groups: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, &(ref pair, date, options)) in unique.iter().enumerate() {
+        match cached_hist_rate(rate_cache, pair, date, options) {
+            Some(response) => emit_unique(i, Ok(response)),
+            None => groups.entry(pair.as_str()).or_insert_with(Vec::new).push(i),
+        }
+    }
+
+    let clusters: Vec<RequestCluster> = groups.into_iter()
+        .flat_map(|(_, indices)| cluster_requests(indices, &unique, registry))
+        .collect();
+    clusters.par_iter().for_each(|cluster| {
+        if stopped.load(Ordering::Relaxed) {
+            return;
+        }
+        for (i, response) in resolve_cluster(registry, rate_store, rate_cache, &unique, cluster) {
+            emit_unique(i, response);
+        }
+    });
+}
+
+/// Resolves the rates of a batch request, returning the responses in the order of the requests.
+fn resolve_batch(
+    registry: &PairRegistry, rate_store: &RateStore, rate_cache: &RateCache, requests: &BatchRateRequest,
+    default_options: RateOptions
+) -> Vec<RateResponse> {
+    let responses: Mutex<Vec<Option<RateResponse>>> = Mutex::new(vec![None; requests.0.len()]);
+    resolve_batch_with(registry, rate_store, rate_cache, requests, default_options, |index, response| {
+        responses.lock().unwrap()[index] = Some(response);
+        true
+    });
+
+    responses.into_inner().unwrap()
+        .into_iter()
+        .map(|response| response.expect("Every request of a batch is answered."))
+        .collect()
+}
+
+/// A line of a batch that's streamed as newline-delimited JSON.  Rates are sent in the order that they're resolved in,
+/// so each of them carries the position of its request in the batch.
+#[derive(Serialize, Deserialize)]
+pub struct StreamedRate {
+    pub index: usize,
+    #[serde(flatten)]
+    pub response: RateResponse,
+}
+
+/// Serializes a line of a batch that's streamed as newline-delimited JSON.
+fn ndjson_line<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut line = serde_json::to_vec(value).expect("Unable to serialize a line of a streamed batch!");
+    line.push(b'\n');
+    line
+}
+
+/// Reads the lines of a streamed batch as the task that resolves the batch sends them.
+pub struct StreamedBatch {
+    lines: Receiver<Vec<u8>>,
+    line: Vec<u8>,
+    position: usize,
+}
+
+impl Read for StreamedBatch {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.line.len() {
+            match self.lines.recv() {
+                Ok(line) => {
+                    self.line = line;
+                    self.position = 0;
+                },
+                // the resolving task is done once it has dropped its sender
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let len = cmp::min(buf.len(), self.line.len() - self.position);
+        buf[..len].copy_from_slice(&self.line[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
     }
 }
 
-/// Implement CORS for `OPTION` queries on the historical rate API
+/// Starts reading and resolving a batch on the Rayon thread pool and returns the newline-delimited JSON lines of its rates
+/// as they're resolved.  At most `STREAM_BUFFER_LINES` lines are buffered, so resolution waits for slow clients and stops
+/// as soon as the client has disconnected and the lines can't be delivered anymore.  If the body turns out to be invalid
+/// after rates have already been sent, the error is sent as the last line.
+fn stream_batch(
+    registry: Arc<PairRegistry>, rate_store: Arc<RateStore>, rate_cache: RateCache, body: BatchBody,
+    default_options: RateOptions
+) -> StreamedBatch {
+    let (sender, receiver) = mpsc::sync_channel(STREAM_BUFFER_LINES);
+    rayon::spawn(move || {
+        let sender = Mutex::new(sender);
+        let disconnected = AtomicBool::new(false);
+        // sending blocks while the buffer is full and fails once the response, and with it the receiver, has been dropped
+        let send = |line: Vec<u8>| {
+            if disconnected.load(Ordering::Relaxed) {
+                return false;
+            }
+            let sent = sender.lock().unwrap().send(line).is_ok();
+            if !sent {
+                disconnected.store(true, Ordering::Relaxed);
+            }
+            sent
+        };
+
+        let read = body.read_chunks(|offset, requests| {
+            resolve_batch_with(&registry, &*rate_store, &rate_cache, &requests, default_options, |index, response| {
+                send(ndjson_line(&StreamedRate { index: offset + index, response: response }))
+            });
+            !disconnected.load(Ordering::Relaxed)
+        });
+        if let Err(err) = read {
+            if !disconnected.load(Ordering::Relaxed) {
+                send(ndjson_line(&ErrorEnvelope { error: err.body() }));
+            }
+        }
+    });
 
-#[route(OPTIONS, "/rate/<pair>/<timestamp_string>")]
-#[allow(unused_variables)]
-fn rate_options_handler<'a>(pair: String, timestamp_string: String) -> Response<'a> {
-    Response::build()
-        .raw_header("Access-Control-Allow-Origin", "http://host.tld")
-        .raw_header("Access-Control-Allow-Methods", "OPTIONS, POST")
-        .raw_header("Access-Control-Allow-Headers", "Content-Type")
-        .finalize()
+    StreamedBatch { lines: receiver, line: Vec::new(), position: 0 }
 }
 
-/// Implement CORS for `OPTION` queries on the historical batch rate API
-#[route(OPTIONS, "/batch_rate")]
-#[allow(unused_variables)]
-fn batch_rate_options_handler<'a>() -> Response<'a> {
-    Response::build()
-        .raw_header("Access-Control-Allow-Origin", "http://host.tld")
-        .raw_header("Access-Control-Allow-Methods", "OPTIONS, POST")
-        .raw_header("Access-Control-Allow-Headers", "Content-Type")
-        .finalize()
+/// Whether the client asked for a batch to be streamed as newline-delimited JSON by preferring `application/x-ndjson` over
+/// all other media types that it accepts
+pub struct BatchFormat {
+    ndjson: bool,
 }
 
-/// Implement CORS for `OPTION` queries on the feedback submission API
-#[route(OPTIONS, "/feedback")]
-#[allow(unused_variables)]
-fn feedback_options_handler<'a>() -> Response<'a> {
-    Response::build()
-        .raw_header("Access-Control-Allow-Origin", "http://host.tld")
-        .raw_header("Access-Control-Allow-Methods", "OPTIONS, POST")
-        .raw_header("Access-Control-Allow-Headers", "Content-Type")
-        .finalize()
+impl<'a, 'r> FromRequest<'a, 'r> for BatchFormat {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let ndjson = request.accept()
+            .map(|accept| {
+                let preferred = accept.preferred();
+                let media_type = preferred.media_type();
+                // a weight of 0 means that the media type isn't acceptable at all
+                media_type.top() == "application" && media_type.sub() == "x-ndjson" && preferred.weight_or(1.) > 0.
+            })
+            .unwrap_or(false);
+
+        Success(BatchFormat { ndjson: ndjson })
+    }
+}
+
+/// The response of the batch rate API, which either holds all rates at once or streams them as they're resolved
+pub enum BatchResponse {
+    Complete(Json<Vec<RateResponse>>),
+    Streamed(Stream<StreamedBatch>),
+}
+
+impl<'r> Responder<'r> for BatchResponse {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        match self {
+            BatchResponse::Complete(rates) => rates.respond_to(request),
+            BatchResponse::Streamed(lines) => Response::build_from(lines.respond_to(request)?)
+                .header(ContentType::new("application", "x-ndjson"))
+                .ok(),
+        }
+    }
 }
 
 /// Exposes the historical rate API.  Attempts to find the nearest exchange rate for the given currency pair and timestamp
-/// within one day on either side.
+/// within one day on either side.  The rate is resolved using the mode and maximum distance in seconds supplied in the
+/// `mode` and `max_distance` query parameters.  The timestamp may be supplied in any format accepted by
+/// `timestamp::parse`.  Unavailable pairs and malformed timestamps are answered with an error.
 #[get("/rate/<pair>/<timestamp_string>")]
 pub fn get_hist_rate(
-    db_pool: State<DbPool>, rate_cache_state: State<RateCache>, pair: String, timestamp_string: String
-) -> Result<Json<RateResponse>, String> {
+    registry: State<Arc<PairRegistry>>, rate_store: State<Arc<RateStore>>, rate_cache_state: State<RateCache>,
+    options: RateOptions, pair: String, timestamp_string: String
+) -> Result<Json<RateResponse>, ApiError> {
     let rate_cache = rate_cache_state.inner();
 
-    let timestamp_res = NaiveDateTime::parse_from_str(&timestamp_string, MYSQL_DATE_FORMAT)
-        .map_err(debug);
-    let timestamp = match timestamp_res {
-        Ok(timestamp) => timestamp,
-        Err(err) => {
-            return Err(err);
-        },
-    };
+    let timestamp = timestamp::parse(&timestamp_string).map_err(ApiError::InvalidRequest)?;
 
-    let hist_rate = retrieve_hist_rate(&db_pool, rate_cache, pair, timestamp);
-    Ok(Json(hist_rate))
+    retrieve_hist_rate(&registry, &**rate_store, rate_cache, pair, timestamp, options).map(Json)
 }
 
 /// Exposes the historical rate API with batch retrieval capabilities.  Allows for multiple pair/date
-/// rates to be queried at once in a single request.
-#[post("/batch_rate", format = "application/json", data="<requests>")]
+/// rates to be queried at once in a single request.  The options supplied in the query string are used for all rates that
+/// don't supply their own.  Rates that can't be resolved are answered with an error of their own rather than failing
+/// the whole batch.  Bodies larger than `limits.max_body_bytes` are rejected with a 413 and batches of more than
+/// `limits.max_batch_size` rates with a 422.  The body is parsed while the rates are resolved, one chunk of
+/// `BATCH_CHUNK_SIZE` requests at a time.
+///
+/// Clients that prefer `application/x-ndjson` receive the rates as newline-delimited JSON instead, which is sent while the
+/// batch is being resolved rather than once it's done.  Lines are sent in chunks of a few kilobytes rather than one at a
+/// time.  Since the lines arrive in the order that rates are resolved in, each of them contains the `index` of its
+/// request in the batch.  Errors in the body that are only found once rates have been sent end the stream with a line
+/// holding the error instead of an error status.
+#[post("/batch_rate", format = "application/json", data="<body>")]
 pub fn get_batch_hist_rates(
-    db_pool: State<DbPool>, rate_cache_state: State<RateCache>, requests: BatchRateRequest
+    registry: State<Arc<PairRegistry>>, rate_store: State<Arc<RateStore>>, rate_cache_state: State<RateCache>,
+    options: RateOptions, format: BatchFormat, body: Result<BatchBody, ApiError>
+) -> Result<BatchResponse, ApiError> {
+    let body = body?;
+    if format.ndjson {
+        let rate_cache = rate_cache_state.inner().clone();
+        let lines = stream_batch(registry.inner().clone(), rate_store.inner().clone(), rate_cache, body, options);
+        return Ok(BatchResponse::Streamed(Stream::from(lines)));
+    }
+
+    let mut rates = Vec::new();
+    body.read_chunks(|_, requests| {
+        rates.extend(resolve_batch(&registry, &**rate_store, rate_cache_state.inner(), &requests, options));
+        true
+    })?;
+    Ok(BatchResponse::Complete(Json(rates)))
+}
+
+/// Exposes the rate series API, which returns the rates of a pair at regular intervals between the `start` and `end`
+/// query parameters, `step` seconds apart.  Each point is resolved like a request to the historical rate API using the
+/// options supplied in the query string and goes through the rate cache.
+#[get("/series/<pair>")]
+pub fn get_rate_series(
+    registry: State<Arc<PairRegistry>>, rate_store: State<Arc<RateStore>>, rate_cache_state: State<RateCache>,
+    options: RateOptions, series: SeriesRequest, pair: String
 ) -> Json<Vec<RateResponse>> {
     let rate_cache = rate_cache_state.inner();
 
-    // process each of the rate requests one by one
-    let results: Vec<RateResponse> = requests.0
+    let results: Vec<RateResponse> = series.timestamps()
         .par_iter()
-        .map(|req| {
-            retrieve_hist_rate(&db_pool, rate_cache, req.pair.clone(), req.date)
+        .map(|&timestamp| {
+            retrieve_hist_rate(&registry, &**rate_store, rate_cache, pair.clone(), timestamp, options)
+                .unwrap_or_else(|err| RateResponse::failed(pair.clone(), Some(timestamp), options, err))
         })
         .collect();
 
     Json(results)
 }
 
+/// Exposes the candle API, which returns OHLC candles of a stored pair built from our own data in the shape that TechanJS
+/// expects.  The pair may be supplied in the format used by Poloniex ("BTC_XMR") or like "BTC/XMR".  Candles are only
+/// stored in MySQL, so they aren't available when rates are read from another store.
+#[get("/candles/<pair>")]
+pub fn get_candles(
+    db_pool: Option<State<DbPool>>, registry: State<Arc<PairRegistry>>, request: CandleRequest, pair: String
+) -> Result<Json<Vec<Candle>>, ApiError> {
+    let db_pool = match db_pool {
+        Some(db_pool) => db_pool,
+        None => {
+            let message = String::from("Candles are only available when rates are stored in MySQL.");
+            return Err(ApiError::Unavailable(message));
+        },
+    };
+    let pair_name = pair.replace('/', "_");
+    let pair_id = match split_pair_name(&pair_name).and_then(|(base, quote)| registry.lookup(base, quote)) {
+        Some(pair_id) => pair_id,
+        None => { return Err(ApiError::InvalidPair(pair)); },
+    };
+
+    let db_conn = &*db_pool.get_conn();
+    candles::get_candles(pair_id, request.period, request.start, request.end, db_conn)
+        .map(Json)
+        .map_err(ApiError::Backend)
+}
+
+/// Reports the size of the rate cache and how many lookups it has served since the server was started.
+#[get("/cache/stats")]
+pub fn get_cache_stats(rate_cache: State<RateCache>) -> Json<RateCacheStats> {
+    Json(rate_cache.stats())
+}
+
+/// Lists all currencies and pairs for which rates are stored along with the times of their first and last observations and
+/// their number of observations.
+#[get("/currencies")]
+pub fn get_currencies(registry: State<Arc<PairRegistry>>) -> Json<CurrencyListing> {
+    Json(registry.listing())
+}
+
+/// Whether a request supplied the configured admin token as `Authorization: Bearer <token>`.  Requests never match an
+/// empty token, so administrative endpoints are disabled unless one is configured.
+pub struct AdminAuthorization {
+    authorized: bool,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AdminAuthorization {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let config = match request.guard::<State<AdminConfig>>() {
+            Success(config) => config,
+            _ => { return Success(AdminAuthorization { authorized: false }); },
+        };
+        let authorized = !config.token.is_empty() && request.headers().get("Authorization")
+            .any(|header| header.trim() == format!("Bearer {}", config.token));
+
+        Success(AdminAuthorization { authorized: authorized })
+    }
+}
+
+/// Reloads the currency registry from the rate store so that newly added pairs become available, then lists all currencies
+/// and pairs like the `/currencies` endpoint.  Since every refresh scans all stored rates, requests must supply the
+/// configured admin token.
+#[post("/currencies/refresh")]
+pub fn refresh_currencies(
+    authorization: AdminAuthorization, rate_store: State<Arc<RateStore>>, registry: State<Arc<PairRegistry>>
+) -> Result<Json<CurrencyListing>, ApiError> {
+    if !authorization.authorized {
+        return Err(ApiError::Unauthorized);
+    }
+
+    match registry.refresh(&**rate_store) {
+        Ok(true) => println!("Refreshed the currency registry."),
+        Ok(false) => println!("Not refreshing the currency registry since it was refreshed recently."),
+        Err(err) => {
+            println!("Error while refreshing the currency registry: {}", err);
+            return Err(ApiError::Backend(err));
+        },
+    }
+
+    Ok(Json(registry.listing()))
+}
+
 /// Exposes an endpoint that interfaces with AmeoTrack to email me feedback that's sent using the feedback modal.
 #[post("/feedback", data="<feedback>")]
-fn submit_feedback(feedback: Json<Feedback>) -> Json<FeedbackResponse> {
-    Json(match deliver_feedback(&feedback.0.email, &feedback.0.message) {
+fn submit_feedback(feedback_config: State<FeedbackConfig>, feedback: Json<Feedback>) -> Json<FeedbackResponse> {
+    Json(match deliver_feedback(&feedback_config, &feedback.0.email, &feedback.0.message) {
         Ok(()) => FeedbackResponse{success: true},
         Err(err) => {
             println!("Error while delivering feedback to AmeoTrack: {}", err);
@@ -229,3 +1038,324 @@ fn submit_feedback(feedback: Json<Feedback>) -> Json<FeedbackResponse> {
         },
     })
 }
+
+#[test]
+fn test_series_request_parsing() {
+    let series = SeriesRequest::parse("start=2017-01-01%2000:00:00&end=2017-01-01%2001:00:00&step=1500&mode=previous").unwrap();
+    assert_eq!(series.step, 1500);
+    let timestamps: Vec<String> = series.timestamps().iter().map(timestamp::format).collect();
+    assert_eq!(timestamps, vec!["2017-01-01T00:00:00Z", "2017-01-01T00:25:00Z", "2017-01-01T00:50:00Z"]);
+    assert_eq!(SeriesRequest::parse("start=1483228800&end=2017-01-01T03:00:00%2B03:00&step=60").unwrap().end, series.start);
+
+    assert!(SeriesRequest::parse("start=2017-01-01%2000:00:00&end=2017-01-01%2001:00:00").is_err());
+    assert!(SeriesRequest::parse("start=2017-01-01%2000:00:00&end=2017-01-01%2001:00:00&step=0").is_err());
+    assert!(SeriesRequest::parse("start=2017-01-02%2000:00:00&end=2017-01-01%2000:00:00&step=60").is_err());
+    assert!(SeriesRequest::parse("start=2017-01-01%2000:00:00&end=2018-01-01%2000:00:00&step=60").is_err());
+}
+
+#[test]
+fn test_candle_request_parsing() {
+    let request = CandleRequest::parse("start=1483228800.5&end=1483315200&period=14400").unwrap();
+    assert_eq!(request.start, NaiveDateTime::from_timestamp(1483228800, 0));
+    assert_eq!(request.period, 14400);
+
+    assert!(CandleRequest::parse("start=1483228800&end=1483315200").is_err());
+    assert!(CandleRequest::parse("start=1483228800&end=1483315200&period=60").is_err());
+    assert!(CandleRequest::parse("start=1483315200&end=1483228800&period=300").is_err());
+    assert!(CandleRequest::parse("start=0&end=1483228800&period=300").is_err());
+    // timestamps that can't be represented are rejected rather than wrapped around or causing a panic
+    assert!(CandleRequest::parse("start=1e18&end=1e18&period=300").is_err());
+    assert!(CandleRequest::parse("start=NaN&end=NaN&period=300").is_err());
+    assert!(CandleRequest::parse("start=inf&end=inf&period=300").is_err());
+    assert!(CandleRequest::parse("start=-1&end=1483228800&period=300").is_err());
+}
+
+#[test]
+fn test_batch_limits() {
+    let limits = LimitsConfig { max_body_bytes: 200, max_batch_size: 2 };
+    let item = "{\"pair\":\"BTC/XMR\",\"date\":1483228800}";
+
+    let parse_batch = |body: &str, limits: &LimitsConfig| -> Result<Vec<_>, ApiError> {
+        let mut requests = Vec::new();
+        let count = read_batch(body.as_bytes(), limits, |offset, chunk| {
+            assert_eq!(offset, requests.len());
+            requests.extend(chunk.0);
+            true
+        })?;
+        assert_eq!(count, requests.len());
+        Ok(requests)
+    };
+
+    let batch = parse_batch(&format!("[{}, {}]", item, item), &limits).unwrap();
+    assert_eq!(batch.len(), 2);
+    assert!(batch.iter().all(|request| request.is_ok()));
+    assert_eq!(parse_batch(" [] ", &limits).map(|batch| batch.len()), Ok(0));
+
+    let err = parse_batch(&format!("[{}, {}, {}]", item, item, item), &limits).err().unwrap();
+    assert_eq!(err, ApiError::BatchTooLarge(2));
+    assert_eq!(err.status(), Status::UnprocessableEntity);
+    let padded = format!("[{}{}]", item, " ".repeat(200));
+    let err = parse_batch(&padded, &limits).err().unwrap();
+    assert_eq!(err.status(), Status::PayloadTooLarge);
+
+    for body in &[format!("[{}] []", item), String::from(item)] {
+        assert_eq!(parse_batch(body, &limits).err().map(|err| err.code()), Some("invalid_request"));
+    }
+
+    // large batches are handed over in chunks while they're still being parsed
+    let limits = LimitsConfig { max_body_bytes: 1024 * 1024, max_batch_size: 2500 };
+    let body = format!("[{}]", vec![item; 2500].join(","));
+    let mut chunks = Vec::new();
+    let count = read_batch(body.as_bytes(), &limits, |offset, chunk| {
+        chunks.push((offset, chunk.0.len()));
+        true
+    });
+    assert_eq!(count, Ok(2500));
+    assert_eq!(chunks, vec![(0, BATCH_CHUNK_SIZE), (1000, BATCH_CHUNK_SIZE), (2000, 500)]);
+    // reading stops as soon as a chunk is refused
+    let mut calls = 0;
+    assert!(read_batch(body.as_bytes(), &limits, |_, _| { calls += 1; false }).is_err());
+    assert_eq!(calls, 1);
+}
+
+#[test]
+fn test_batch_resolution() {
+    use index::RateIndex;
+    use normalized::PairInfo;
+    use registry::PairMetadata;
+
+    let time = |minutes: i64| NaiveDateTime::from_timestamp(1483228800 + (minutes * 60), 0);
+    let pair = |id: i32, quote: &str| {
+        PairMetadata::new(&PairInfo { id: id, base: String::from("BTC"), quote: String::from(quote) }, None)
+    };
+    let store = RateIndex::from_observations(vec![
+        (pair(1, "XMR"), vec![(time(0), 0.25), (time(10), 0.5), (time(3 * 24 * 60), 1.)]),
+        (pair(2, "USD"), vec![(time(5), 1000.)]),
+    ]);
+    let registry = PairRegistry::load(&store).unwrap();
+    let rate_cache = RateCache::new(100);
+    let request = |pair: &str, date: NaiveDateTime| {
+        Ok(RateRequest { date: date, pair: String::from(pair), mode: None, max_distance: None, bucket: None })
+    };
+    let batch = BatchRateRequest(vec![
+        request("BTC/XMR", time(4)),
+        request("XMR/USD", time(10)),
+        Err((String::from("BTC/XMR"), ApiError::InvalidRequest(String::from("Invalid date supplied: yesterday")))),
+        request("BTC/XMR", time(3 * 24 * 60)),
+        request("BTC/XMR", time(4)),
+        request("ETH/USD", time(10)),
+    ]);
+
+    let responses = resolve_batch(&registry, &store, &rate_cache, &batch, RateOptions::default());
+    let rates: Vec<Option<f32>> = responses.iter().map(|res| res.rate).collect();
+    assert_eq!(rates, vec![Some(0.25), Some(500.), None, Some(1.), Some(0.25), None]);
+    assert_eq!(responses[2].error.as_ref().map(|err| err.code.as_str()), Some("invalid_request"));
+    assert_eq!(responses[5].error.as_ref().map(|err| err.code.as_str()), Some("invalid_pair"));
+    // the duplicate request was only looked up once
+    assert_eq!(rate_cache.stats().misses, 4);
+
+    let responses = resolve_batch(&registry, &store, &rate_cache, &batch, RateOptions::default());
+    assert!(responses[0].cached && responses[1].cached && responses[4].cached);
+
+    // timestamps days apart are loaded separately
+    let keys: Vec<BatchKey> = vec![time(4), time(60), time(3 * 24 * 60)].into_iter()
+        .map(|date| (String::from("BTC/XMR"), date, RateOptions::default()))
+        .collect();
+    let clusters = cluster_requests(vec![2, 0, 1], &keys, &registry);
+    let requests: Vec<Vec<usize>> = clusters.iter().map(|cluster| cluster.requests.clone()).collect();
+    assert_eq!(requests, vec![vec![0, 1], vec![2]]);
+    assert_eq!(clusters[0].pair_ids, vec![1]);
+    assert_eq!(clusters[0].window, Some((time(4) - Duration::hours(4), time(60) + Duration::hours(4))));
+}
+
+#[test]
+fn test_refresh_authorization() {
+    use rocket::http::Header;
+    use rocket::local::Client;
+
+    use config::Config;
+    use error::ErrorEnvelope;
+    use index::RateIndex;
+
+    let mut config = Config::default();
+    config.admin.token = String::from("s3cret");
+    let rate_store: Arc<RateStore> = Arc::new(RateIndex::from_observations(Vec::new()));
+    let client = Client::new(::rocket(&config, RateCache::new(0), rate_store, None)).unwrap();
+
+    for authorization in &[None, Some("Bearer wrong"), Some("s3cret"), Some("Bearer ")] {
+        let mut req = client.post("/currencies/refresh");
+        if let Some(authorization) = *authorization {
+            req = req.header(Header::new("Authorization", authorization));
+        }
+        let mut res = req.dispatch();
+        assert_eq!(res.status(), Status::Unauthorized);
+        let envelope: ErrorEnvelope = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+        assert_eq!(envelope.error.code, "unauthorized");
+    }
+    let res = client.post("/currencies/refresh").header(Header::new("Authorization", "Bearer s3cret")).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+
+    // without a configured token, the endpoint can't be used at all
+    let rate_store: Arc<RateStore> = Arc::new(RateIndex::from_observations(Vec::new()));
+    let client = Client::new(::rocket(&Config::default(), RateCache::new(0), rate_store, None)).unwrap();
+    let res = client.post("/currencies/refresh").header(Header::new("Authorization", "Bearer ")).dispatch();
+    assert_eq!(res.status(), Status::Unauthorized);
+}
+
+#[test]
+fn test_batch_format_negotiation() {
+    use rocket::http::Header;
+    use rocket::local::Client;
+
+    use config::Config;
+    use index::RateIndex;
+    use normalized::PairInfo;
+    use registry::PairMetadata;
+
+    let pair = PairMetadata::new(&PairInfo { id: 1, base: String::from("BTC"), quote: String::from("XMR") }, None);
+    let time = NaiveDateTime::from_timestamp(1483228800, 0);
+    let rate_store: Arc<RateStore> = Arc::new(RateIndex::from_observations(vec![(pair, vec![(time, 0.25)])]));
+    let client = Client::new(::rocket(&Config::default(), RateCache::new(0), rate_store, None)).unwrap();
+
+    let ndjson = ContentType::new("application", "x-ndjson");
+    for &(accept, expected) in &[
+        ("application/x-ndjson", &ndjson),
+        ("application/json, application/x-ndjson;q=0.5", &ContentType::JSON),
+        ("application/json;q=0.5, application/x-ndjson", &ndjson),
+        ("application/x-ndjson;q=0", &ContentType::JSON),
+    ] {
+        let mut res = client.post("/batch_rate")
+            .header(ContentType::JSON)
+            .header(Header::new("Accept", accept))
+            .body("[{\"pair\":\"BTC/XMR\",\"date\":\"2017-01-01 00:00:00\"}]")
+            .dispatch();
+        assert_eq!(res.content_type().as_ref(), Some(expected));
+        assert!(res.body_string().unwrap().contains("0.25"));
+    }
+
+    // a streamed batch whose body turns out to be invalid ends with the error
+    let mut res = client.post("/batch_rate")
+        .header(ContentType::JSON)
+        .header(Header::new("Accept", "application/x-ndjson"))
+        .body("[{\"pair\":\"BTC/XMR\",\"date\":\"2017-01-01 00:00:00\"} {")
+        .dispatch();
+    let body = res.body_string().unwrap();
+    let last: ErrorEnvelope = serde_json::from_str(body.lines().last().unwrap()).unwrap();
+    assert_eq!(last.error.code, "invalid_request");
+}
+
+#[cfg(test)]
+const HOSTILE_PAIRS: &[&'static str] = &[
+    "BTC/XMR'; DROP TABLE rates; --",
+    "BTC/XMR` WHERE 1=1; --",
+    "BTC/XMR\" OR \"1\"=\"1",
+    "' OR '1'='1/BTC",
+    "BTC/DOGE) UNION SELECT 1, 1 FROM currencies --",
+    "BTC/XMR\\",
+    "BTC/XMR\u{0}",
+    "BTC/",
+    "/",
+];
+
+#[test]
+fn test_hostile_rate_requests() {
+    use rocket::http::Header;
+    use rocket::http::uri::URI;
+    use rocket::local::Client;
+
+    use config::Config;
+    use db_query::create_db_pool;
+    use error::ErrorEnvelope;
+
+    let config = Config::load().unwrap();
+    let rate_cache = RateCache::new(config.cache.capacity);
+    let db_pool = DbPool(create_db_pool(&config.database));
+    let rate_store: Arc<RateStore> = Arc::new(DbPool(db_pool.0.clone()));
+    let client = Client::new(::rocket(&config, rate_cache, rate_store, Some(db_pool))).unwrap();
+
+    for pair in HOSTILE_PAIRS {
+        let url = format!("/rate/{}/{}", URI::percent_encode(pair), URI::percent_encode("2014-01-25 05:44:38"));
+        let mut res = client.get(url).dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+        let envelope: ErrorEnvelope = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+        assert_eq!(envelope.error.code, "invalid_pair");
+    }
+
+    let hostile_timestamps = [
+        "2014-01-25 05:44:38' OR '1'='1",
+        "2014-01-25 05:44:38; DROP TABLE rates; --",
+        "' OR 1=1 --",
+    ];
+    for timestamp in &hostile_timestamps {
+        let url = format!("/rate/{}/{}", URI::percent_encode("BTC/DOGE"), URI::percent_encode(timestamp));
+        let mut res = client.get(url).dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+        let envelope: ErrorEnvelope = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+        assert_eq!(envelope.error.code, "invalid_request");
+    }
+
+    let url = format!("/rate/{}/{}?mode={}", URI::percent_encode("BTC/DOGE"), URI::percent_encode("2014-01-25 05:44:38"), "future");
+    let mut res = client.get(url).dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+    assert_eq!(res.content_type(), Some(ContentType::JSON));
+    assert!(serde_json::from_str::<ErrorEnvelope>(&res.body_string().unwrap()).is_ok());
+    for max_distance in &["0", "-1", "99999999999", "1e3"] {
+        let url = format!("/rate/{}/{}?max_distance={}", URI::percent_encode("BTC/DOGE"), URI::percent_encode("2014-01-25 05:44:38"), max_distance);
+        assert_eq!(client.get(url).dispatch().status(), Status::BadRequest);
+    }
+
+    let mut batch: Vec<String> = HOSTILE_PAIRS.iter()
+        .map(|pair| format!("{{\"pair\":{},\"date\":\"2014-01-25 05:44:38\"}}", serde_json::to_string(pair).unwrap()))
+        .collect();
+    // malformed entries only fail themselves rather than the whole batch
+    batch.push(String::from("{\"pair\":\"BTC/DOGE\",\"date\":\"2014-01-25 05:44:38' OR '1'='1\"}"));
+    batch.push(String::from("{\"pair\":\"BTC/DOGE\",\"date\":\"2014-01-25 05:44:38\",\"mode\":\"future\"}"));
+    batch.push(String::from("{\"pair\":\"BTC/DOGE\",\"date\":\"2014-01-25 05:44:38\"}"));
+    let mut res = client.post("/batch_rate")
+        .header(ContentType::JSON)
+        .body(format!("[{}]", batch.join(",")))
+        .dispatch();
+    let rates: Vec<RateResponse> = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(rates.len(), HOSTILE_PAIRS.len() + 3);
+    let error_codes: Vec<&str> = rates.iter()
+        .map(|rate| rate.error.as_ref().map(|err| err.code.as_str()).unwrap_or(""))
+        .collect();
+    assert!(error_codes[..HOSTILE_PAIRS.len()].iter().all(|&code| code == "invalid_pair"));
+    assert_eq!(&error_codes[HOSTILE_PAIRS.len()..], &["invalid_request", "invalid_request", ""]);
+    assert_eq!(rates[HOSTILE_PAIRS.len()].date, None);
+    assert_eq!(rates[HOSTILE_PAIRS.len() + 2].rate, Some(0.0000015));
+
+    // streamed batches contain the same rates, one per line, along with the positions of their requests
+    let mut res = client.post("/batch_rate")
+        .header(ContentType::JSON)
+        .header(Header::new("Accept", "application/x-ndjson"))
+        .body(format!("[{}]", batch.join(",")))
+        .dispatch();
+    assert_eq!(res.content_type(), Some(ContentType::new("application", "x-ndjson")));
+    let mut streamed: Vec<StreamedRate> = res.body_string().unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    streamed.sort_by_key(|rate| rate.index);
+    assert_eq!(streamed.iter().map(|rate| rate.index).collect::<Vec<_>>(), (0..rates.len()).collect::<Vec<_>>());
+    assert!(streamed.iter().zip(rates.iter()).all(|(streamed, rate)| streamed.response.error == rate.error));
+    assert_eq!(streamed[HOSTILE_PAIRS.len() + 2].response.rate, Some(0.0000015));
+
+    let res = client.post("/batch_rate").header(ContentType::JSON).body("{\"pair\":\"BTC/DOGE\"}").dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+
+    // the stored data should still be intact
+    let url = format!("/rate/{}/{}", URI::percent_encode("BTC/DOGE"), URI::percent_encode("2014-01-25 05:44:38"));
+    let rate: RateResponse = serde_json::from_str(&client.get(url).dispatch().body_string().unwrap()).unwrap();
+    assert_eq!(rate.rate, Some(0.0000015));
+    assert_eq!(rate.mode, RateMode::Nearest);
+
+    // the same timestamp in any of the other accepted formats resolves to the same rate
+    for timestamp in &["1390628678", "1390628678000", "2014-01-25T06:44:38+01:00"] {
+        let url = format!("/rate/{}/{}", URI::percent_encode("BTC/DOGE"), URI::percent_encode(timestamp));
+        let body = client.get(url).dispatch().body_string().unwrap();
+        assert!(body.contains("\"date\":\"2014-01-25T05:44:38Z\""));
+        let rate: RateResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(rate.rate, Some(0.0000015));
+    }
+}
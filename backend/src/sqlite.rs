@@ -0,0 +1,220 @@
+//! Rate store backed by a SQLite file, which lets the backend run without a MySQL server.  The file uses the same
+//! `currencies`, `pairs` and `rates` tables as the normalized MySQL storage and can be created from it with
+//! `migrate --sqlite <path>`.  Only available with the `sqlite` feature.
+//!
+//! SQLite connections can't be shared between threads, so all queries go through a single connection one at a time.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::NaiveDateTime;
+use diesel;
+use diesel::prelude::*;
+use diesel::expression::sql_literal::sql;
+use diesel::sqlite::SqliteConnection;
+use diesel::types::{BigInt, Integer, Timestamp};
+
+use super::debug;
+use normalized::{currencies, pairs, rates, PairInfo};
+use registry::PairMetadata;
+use store::RateStore;
+
+#[derive(Insertable)]
+#[table_name="currencies"]
+struct NewCurrency<'a> {
+    symbol: &'a str,
+}
+
+#[derive(Insertable)]
+#[table_name="pairs"]
+struct NewPair {
+    id: i32,
+    base_id: i32,
+    quote_id: i32,
+}
+
+#[derive(Insertable)]
+#[table_name="rates"]
+struct NewRate {
+    pair_id: i32,
+    trade_time: NaiveDateTime,
+    rate: f32,
+}
+
+pub struct SqliteStore(Mutex<SqliteConnection>);
+
+impl SqliteStore {
+    /// Opens the SQLite file at the given path, creating it along with the tables if it doesn't exist.
+    pub fn open(path: &str) -> Result<SqliteStore, String> {
+        let conn = SqliteConnection::establish(path).map_err(debug)?;
+        create_tables(&conn)?;
+        Ok(SqliteStore(Mutex::new(conn)))
+    }
+
+    /// Stores the supplied pair under its ID along with its currencies, removing any observations stored for it.
+    pub fn register_pair(&self, pair: &PairMetadata) -> Result<(), String> {
+        let conn = &*self.0.lock().unwrap();
+
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            let base_id = register_currency(&pair.base, conn)?;
+            let quote_id = register_currency(&pair.quote, conn)?;
+            diesel::delete(rates::table.filter(rates::pair_id.eq(pair.id))).execute(conn)?;
+            diesel::delete(pairs::table.filter(pairs::id.eq(pair.id))).execute(conn)?;
+            diesel::insert(&NewPair { id: pair.id, base_id: base_id, quote_id: quote_id })
+                .into(pairs::table)
+                .execute(conn)?;
+            Ok(())
+        }).map_err(debug)
+    }
+
+    /// Stores the supplied observations of a registered pair.
+    pub fn insert_rates(&self, pair_id: i32, observations: &[(NaiveDateTime, f32)]) -> Result<(), String> {
+        let conn = &*self.0.lock().unwrap();
+
+        // SQLite commits every statement outside of a transaction to disk, which is very slow for many small inserts
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            for &(time, rate) in observations {
+                diesel::insert(&NewRate { pair_id: pair_id, trade_time: time, rate: rate })
+                    .into(rates::table)
+                    .execute(conn)?;
+            }
+            Ok(())
+        }).map_err(debug)
+    }
+}
+
+fn create_tables(conn: &SqliteConnection) -> Result<(), String> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS currencies (
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            symbol VARCHAR(16) NOT NULL UNIQUE
+        );"
+    ).map_err(debug)?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS pairs (
+            id INTEGER PRIMARY KEY NOT NULL,
+            base_id INTEGER NOT NULL REFERENCES currencies(id),
+            quote_id INTEGER NOT NULL REFERENCES currencies(id),
+            UNIQUE (base_id, quote_id)
+        );"
+    ).map_err(debug)?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS rates (
+            pair_id INTEGER NOT NULL REFERENCES pairs(id),
+            trade_time TIMESTAMP NOT NULL,
+            rate FLOAT NOT NULL,
+            PRIMARY KEY (pair_id, trade_time)
+        );"
+    ).map_err(debug)?;
+
+    Ok(())
+}
+
+/// Returns the ID of the currency with the given symbol, creating it if it doesn't already exist.
+fn register_currency(symbol: &str, conn: &SqliteConnection) -> QueryResult<i32> {
+    let query = currencies::table.filter(currencies::symbol.eq(symbol)).select(currencies::id);
+
+    if let Some(id) = query.first(conn).optional()? {
+        return Ok(id);
+    }
+    diesel::insert(&NewCurrency { symbol: symbol }).into(currencies::table).execute(conn)?;
+    query.first(conn)
+}
+
+impl RateStore for SqliteStore {
+    fn list_pairs(&self) -> Result<Vec<PairMetadata>, String> {
+        let conn = &*self.0.lock().unwrap();
+
+        let symbols: HashMap<i32, String> = currencies::table
+            .load::<(i32, String)>(conn)
+            .map_err(debug)?
+            .into_iter()
+            .collect();
+        let stats: HashMap<i32, (NaiveDateTime, NaiveDateTime, i64)> = sql::<(Integer, Timestamp, Timestamp, BigInt)>(
+            "SELECT pair_id, MIN(trade_time), MAX(trade_time), COUNT(*) FROM rates GROUP BY pair_id"
+        ).load::<(i32, NaiveDateTime, NaiveDateTime, i64)>(conn)
+            .map_err(debug)?
+            .into_iter()
+            .map(|(pair_id, first, last, count)| (pair_id, (first, last, count)))
+            .collect();
+
+        let mut pairs: Vec<PairMetadata> = pairs::table
+            .load::<(i32, i32, i32)>(conn)
+            .map_err(debug)?
+            .into_iter()
+            .filter_map(|(id, base_id, quote_id)| match (symbols.get(&base_id), symbols.get(&quote_id)) {
+                (Some(base), Some(quote)) => {
+                    let pair = PairInfo { id: id, base: base.clone(), quote: quote.clone() };
+                    Some(PairMetadata::new(&pair, stats.get(&id).cloned()))
+                },
+                _ => None,
+            })
+            .collect();
+        pairs.sort_by(|a, b| a.pair.cmp(&b.pair));
+
+        Ok(pairs)
+    }
+
+    fn before(
+        &self, pair_id: i32, timestamp: NaiveDateTime, search_start: NaiveDateTime
+    ) -> Result<Option<(NaiveDateTime, f32)>, String> {
+        use normalized::rates::dsl;
+
+        dsl::rates
+            .filter(dsl::pair_id.eq(pair_id))
+            .filter(dsl::trade_time.le(timestamp))
+            .filter(dsl::trade_time.ge(search_start))
+            .order(dsl::trade_time.desc())
+            .select((dsl::trade_time, dsl::rate))
+            .first(&*self.0.lock().unwrap())
+            .optional()
+            .map_err(debug)
+    }
+
+    fn after(
+        &self, pair_id: i32, timestamp: NaiveDateTime, search_end: NaiveDateTime
+    ) -> Result<Option<(NaiveDateTime, f32)>, String> {
+        use normalized::rates::dsl;
+
+        dsl::rates
+            .filter(dsl::pair_id.eq(pair_id))
+            .filter(dsl::trade_time.gt(timestamp))
+            .filter(dsl::trade_time.le(search_end))
+            .order(dsl::trade_time.asc())
+            .select((dsl::trade_time, dsl::rate))
+            .first(&*self.0.lock().unwrap())
+            .optional()
+            .map_err(debug)
+    }
+
+    fn range(&self, pair_id: i32, start: NaiveDateTime, end: NaiveDateTime) -> Result<Vec<(NaiveDateTime, f32)>, String> {
+        use normalized::rates::dsl;
+
+        dsl::rates
+            .filter(dsl::pair_id.eq(pair_id))
+            .filter(dsl::trade_time.ge(start))
+            .filter(dsl::trade_time.le(end))
+            .order(dsl::trade_time.asc())
+            .select((dsl::trade_time, dsl::rate))
+            .load(&*self.0.lock().unwrap())
+            .map_err(debug)
+    }
+}
+
+#[test]
+fn test_sqlite_store() {
+    let time = |minutes: i64| NaiveDateTime::from_timestamp(1483228800 + (minutes * 60), 0);
+
+    let store = SqliteStore::open(":memory:").unwrap();
+    let pair = PairMetadata::new(&PairInfo { id: 3, base: String::from("BTC"), quote: String::from("XMR") }, None);
+    store.register_pair(&pair).unwrap();
+    store.insert_rates(3, &[(time(0), 1.), (time(10), 2.), (time(20), 3.)]).unwrap();
+
+    let pairs = store.list_pairs().unwrap();
+    assert_eq!(pairs.len(), 1);
+    assert_eq!((pairs[0].id, pairs[0].pair.as_str(), pairs[0].observation_count), (3, "BTC/XMR", 3));
+    assert_eq!(pairs[0].last_observation, Some(time(20)));
+    assert_eq!(store.nearest(3, time(14), time(0), time(30)).unwrap(), Some((time(10), 2.)));
+    assert_eq!(store.after(3, time(20), time(30)).unwrap(), None);
+    assert_eq!(store.range(3, time(5), time(20)).unwrap(), vec![(time(10), 2.), (time(20), 3.)]);
+}
@@ -0,0 +1,304 @@
+//! Storage backends that rates are resolved from.  The rate APIs only need to find observations near a timestamp, load the
+//! observations in a window, and list the stored pairs, so any store that can do those things can serve them.  Rates are
+//! stored in MySQL in production, but they can also be read from a SQLite file (with the `sqlite` feature) or from memory
+//! so that the backend can run without a MySQL server.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+
+use super::{debug, DbPool};
+use index::RateIndex;
+use registry::{self, PairMetadata};
+
+/// A store holding the observed rates of every pair.  Observations are `(time, rate)` tuples.
+pub trait RateStore: Send + Sync {
+    /// Returns all stored pairs along with statistics about their stored observations.
+    fn list_pairs(&self) -> Result<Vec<PairMetadata>, String>;
+
+    /// Returns the last observation of the given pair at or before the supplied timestamp, but not before `search_start`.
+    fn before(
+        &self, pair_id: i32, timestamp: NaiveDateTime, search_start: NaiveDateTime
+    ) -> Result<Option<(NaiveDateTime, f32)>, String>;
+
+    /// Returns the first observation of the given pair after the supplied timestamp, but not after `search_end`.
+    fn after(
+        &self, pair_id: i32, timestamp: NaiveDateTime, search_end: NaiveDateTime
+    ) -> Result<Option<(NaiveDateTime, f32)>, String>;
+
+    /// Returns all observations of the given pair between `start` and `end`, inclusive, oldest first.
+    fn range(&self, pair_id: i32, start: NaiveDateTime, end: NaiveDateTime) -> Result<Vec<(NaiveDateTime, f32)>, String>;
+
+    /// Returns the observation of the given pair between `search_start` and `search_end` that is nearest to the supplied
+    /// timestamp.
+    fn nearest(
+        &self, pair_id: i32, timestamp: NaiveDateTime, search_start: NaiveDateTime, search_end: NaiveDateTime
+    ) -> Result<Option<(NaiveDateTime, f32)>, String> {
+        Ok(match (self.before(pair_id, timestamp, search_start)?, self.after(pair_id, timestamp, search_end)?) {
+            (Some(before), Some(after)) => Some(nearer(timestamp, before, after)),
+            (before, None) => before,
+            (None, after) => after,
+        })
+    }
+}
+
+/// Returns whichever of the two observations is closer to the supplied timestamp, preferring the earlier one on ties.
+pub fn nearer(timestamp: NaiveDateTime, before: (NaiveDateTime, f32), after: (NaiveDateTime, f32)) -> (NaiveDateTime, f32) {
+    if after.0.signed_duration_since(timestamp) < timestamp.signed_duration_since(before.0) {
+        after
+    } else {
+        before
+    }
+}
+
+/// Rates stored in MySQL in the normalized `rates` table
+impl RateStore for DbPool {
+    fn list_pairs(&self) -> Result<Vec<PairMetadata>, String> {
+        registry::load_pair_metadata(&*self.get_conn())
+    }
+
+    fn before(
+        &self, pair_id: i32, timestamp: NaiveDateTime, search_start: NaiveDateTime
+    ) -> Result<Option<(NaiveDateTime, f32)>, String> {
+        use normalized::rates::dsl;
+
+        dsl::rates
+            .filter(dsl::pair_id.eq(pair_id))
+            .filter(dsl::trade_time.le(timestamp))
+            .filter(dsl::trade_time.ge(search_start))
+            .order(dsl::trade_time.desc())
+            .select((dsl::trade_time, dsl::rate))
+            .first(&*self.get_conn())
+            .optional()
+            .map_err(debug)
+    }
+
+    fn after(
+        &self, pair_id: i32, timestamp: NaiveDateTime, search_end: NaiveDateTime
+    ) -> Result<Option<(NaiveDateTime, f32)>, String> {
+        use normalized::rates::dsl;
+
+        dsl::rates
+            .filter(dsl::pair_id.eq(pair_id))
+            .filter(dsl::trade_time.gt(timestamp))
+            .filter(dsl::trade_time.le(search_end))
+            .order(dsl::trade_time.asc())
+            .select((dsl::trade_time, dsl::rate))
+            .first(&*self.get_conn())
+            .optional()
+            .map_err(debug)
+    }
+
+    fn range(&self, pair_id: i32, start: NaiveDateTime, end: NaiveDateTime) -> Result<Vec<(NaiveDateTime, f32)>, String> {
+        use normalized::rates::dsl;
+
+        dsl::rates
+            .filter(dsl::pair_id.eq(pair_id))
+            .filter(dsl::trade_time.ge(start))
+            .filter(dsl::trade_time.le(end))
+            .order(dsl::trade_time.asc())
+            .select((dsl::trade_time, dsl::rate))
+            .load(&*self.get_conn())
+            .map_err(debug)
+    }
+}
+
+/// Rates held entirely in memory.  The index answers every query itself regardless of which windows it covers.
+impl RateStore for RateIndex {
+    fn list_pairs(&self) -> Result<Vec<PairMetadata>, String> {
+        Ok(self.pairs())
+    }
+
+    fn before(
+        &self, pair_id: i32, timestamp: NaiveDateTime, search_start: NaiveDateTime
+    ) -> Result<Option<(NaiveDateTime, f32)>, String> {
+        Ok(RateIndex::before(self, pair_id, timestamp, search_start))
+    }
+
+    fn after(
+        &self, pair_id: i32, timestamp: NaiveDateTime, search_end: NaiveDateTime
+    ) -> Result<Option<(NaiveDateTime, f32)>, String> {
+        Ok(RateIndex::after(self, pair_id, timestamp, search_end))
+    }
+
+    fn range(&self, pair_id: i32, start: NaiveDateTime, end: NaiveDateTime) -> Result<Vec<(NaiveDateTime, f32)>, String> {
+        Ok(self.between(pair_id, start, end))
+    }
+}
+
+/// Answers queries from an in-memory index where it covers them and from another store otherwise, including all queries
+/// for pairs that were registered after the index was last extended.  Pairs are always listed by the other store since
+/// its statistics include the most recent observations and pairs.
+pub struct IndexedStore {
+    pub index: RateIndex,
+    pub fallback: Arc<RateStore>,
+}
+
+impl RateStore for IndexedStore {
+    fn list_pairs(&self) -> Result<Vec<PairMetadata>, String> {
+        self.fallback.list_pairs()
+    }
+
+    fn before(
+        &self, pair_id: i32, timestamp: NaiveDateTime, search_start: NaiveDateTime
+    ) -> Result<Option<(NaiveDateTime, f32)>, String> {
+        if self.index.covers(pair_id, timestamp) {
+            Ok(self.index.before(pair_id, timestamp, search_start))
+        } else {
+            self.fallback.before(pair_id, timestamp, search_start)
+        }
+    }
+
+    fn after(
+        &self, pair_id: i32, timestamp: NaiveDateTime, search_end: NaiveDateTime
+    ) -> Result<Option<(NaiveDateTime, f32)>, String> {
+        if self.index.covers(pair_id, search_end) {
+            Ok(self.index.after(pair_id, timestamp, search_end))
+        } else {
+            self.fallback.after(pair_id, timestamp, search_end)
+        }
+    }
+
+    fn range(&self, pair_id: i32, start: NaiveDateTime, end: NaiveDateTime) -> Result<Vec<(NaiveDateTime, f32)>, String> {
+        if self.index.covers(pair_id, end) {
+            Ok(self.index.between(pair_id, start, end))
+        } else {
+            self.fallback.range(pair_id, start, end)
+        }
+    }
+}
+
+/// Answers queries within a window of time from observations that were loaded with a single range query per pair, and
+/// queries that reach outside of the window or concern other pairs from another store.  Used to resolve many rates of
+/// the same pairs without querying the other store for each of them.
+pub struct PrefetchedStore<'a> {
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+    observations: HashMap<i32, Vec<(NaiveDateTime, f32)>>,
+    fallback: &'a RateStore,
+}
+
+/// Returns the index of the first of the sorted observations after the supplied time.
+fn upper_bound(observations: &[(NaiveDateTime, f32)], time: NaiveDateTime) -> usize {
+    match observations.binary_search_by(|&(observed, _)| observed.cmp(&time)) {
+        Ok(i) => i + 1,
+        Err(i) => i,
+    }
+}
+
+impl<'a> PrefetchedStore<'a> {
+    /// Loads the observations of the given pairs between `start` and `end` from the store.
+    pub fn load(
+        store: &'a RateStore, pair_ids: &[i32], start: NaiveDateTime, end: NaiveDateTime
+    ) -> Result<PrefetchedStore<'a>, String> {
+        let mut observations = HashMap::new();
+        for &pair_id in pair_ids {
+            if !observations.contains_key(&pair_id) {
+                observations.insert(pair_id, store.range(pair_id, start, end)?);
+            }
+        }
+
+        Ok(PrefetchedStore { start: start, end: end, observations: observations, fallback: store })
+    }
+
+    /// Returns the prefetched observations of the given pair if they cover everything between `start` and `end`.
+    fn covering(&self, pair_id: i32, start: NaiveDateTime, end: NaiveDateTime) -> Option<&[(NaiveDateTime, f32)]> {
+        if start < self.start || end > self.end {
+            return None;
+        }
+        self.observations.get(&pair_id).map(|observations| observations.as_slice())
+    }
+}
+
+impl<'a> RateStore for PrefetchedStore<'a> {
+    fn list_pairs(&self) -> Result<Vec<PairMetadata>, String> {
+        self.fallback.list_pairs()
+    }
+
+    fn before(
+        &self, pair_id: i32, timestamp: NaiveDateTime, search_start: NaiveDateTime
+    ) -> Result<Option<(NaiveDateTime, f32)>, String> {
+        let observations = match self.covering(pair_id, search_start, timestamp) {
+            Some(observations) => observations,
+            None => { return self.fallback.before(pair_id, timestamp, search_start); },
+        };
+
+        Ok(match upper_bound(observations, timestamp) {
+            0 => None,
+            i if observations[i - 1].0 >= search_start => Some(observations[i - 1]),
+            _ => None,
+        })
+    }
+
+    fn after(
+        &self, pair_id: i32, timestamp: NaiveDateTime, search_end: NaiveDateTime
+    ) -> Result<Option<(NaiveDateTime, f32)>, String> {
+        let observations = match self.covering(pair_id, timestamp, search_end) {
+            Some(observations) => observations,
+            None => { return self.fallback.after(pair_id, timestamp, search_end); },
+        };
+
+        Ok(match observations.get(upper_bound(observations, timestamp)) {
+            Some(&observation) if observation.0 <= search_end => Some(observation),
+            _ => None,
+        })
+    }
+
+    fn range(&self, pair_id: i32, start: NaiveDateTime, end: NaiveDateTime) -> Result<Vec<(NaiveDateTime, f32)>, String> {
+        let observations = match self.covering(pair_id, start, end) {
+            Some(observations) => observations,
+            None => { return self.fallback.range(pair_id, start, end); },
+        };
+
+        let first = match observations.binary_search_by(|&(observed, _)| observed.cmp(&start)) {
+            Ok(i) | Err(i) => i,
+        };
+        let last = upper_bound(observations, end);
+        Ok(if first < last { observations[first..last].to_vec() } else { Vec::new() })
+    }
+}
+
+#[test]
+fn test_prefetched_store() {
+    use normalized::PairInfo;
+
+    let time = |minutes: i64| NaiveDateTime::from_timestamp(1483228800 + (minutes * 60), 0);
+    let pair = PairMetadata::new(&PairInfo { id: 1, base: String::from("BTC"), quote: String::from("XMR") }, None);
+    let observations = vec![(time(0), 1.), (time(10), 2.), (time(20), 3.), (time(40), 4.)];
+    let index = RateIndex::from_observations(vec![(pair, observations)]);
+    let store = PrefetchedStore::load(&index, &[1], time(5), time(30)).unwrap();
+
+    assert_eq!(store.observations[&1], vec![(time(10), 2.), (time(20), 3.)]);
+    assert_eq!(store.nearest(1, time(14), time(6), time(22)).unwrap(), Some((time(10), 2.)));
+    assert_eq!(store.before(1, time(20), time(12)).unwrap(), Some((time(20), 3.)));
+    assert_eq!(store.after(1, time(20), time(30)).unwrap(), None);
+    assert_eq!(store.range(1, time(10), time(19)).unwrap(), vec![(time(10), 2.)]);
+    // queries reaching outside of the window go to the other store
+    assert_eq!(store.before(1, time(8), time(0)).unwrap(), Some((time(0), 1.)));
+    assert_eq!(store.after(1, time(20), time(40)).unwrap(), Some((time(40), 4.)));
+}
+
+#[test]
+fn test_indexed_store_new_pairs() {
+    use normalized::PairInfo;
+
+    let time = |minutes: i64| NaiveDateTime::from_timestamp(1483228800 + (minutes * 60), 0);
+    let pair = |id: i32, quote: &str| {
+        PairMetadata::new(&PairInfo { id: id, base: String::from("BTC"), quote: String::from(quote) }, None)
+    };
+    let fallback = RateIndex::from_observations(vec![
+        (pair(1, "XMR"), vec![(time(0), 1.), (time(20), 2.)]),
+        (pair(2, "ETH"), vec![(time(0), 3.), (time(20), 4.)]),
+    ]);
+    // the index was extended before the second pair was registered
+    let index = RateIndex::from_observations(vec![(pair(1, "XMR"), vec![(time(0), 1.), (time(20), 2.)])]);
+    let store = IndexedStore { index: index, fallback: Arc::new(fallback) };
+
+    assert_eq!(store.before(1, time(10), time(0)).unwrap(), Some((time(0), 1.)));
+    assert_eq!(store.before(2, time(10), time(0)).unwrap(), Some((time(0), 3.)));
+    assert_eq!(store.after(2, time(10), time(20)).unwrap(), Some((time(20), 4.)));
+    assert_eq!(store.range(2, time(0), time(20)).unwrap(), vec![(time(0), 3.), (time(20), 4.)]);
+    assert_eq!(store.list_pairs().unwrap().len(), 2);
+}
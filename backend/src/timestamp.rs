@@ -0,0 +1,118 @@
+//! Parsing and formatting of the timestamps accepted and returned by the rate APIs.  Timestamps are stored and resolved
+//! as `NaiveDateTime`s in UTC.  Requests may supply them as Unix timestamps in seconds or milliseconds, as RFC 3339
+//! timestamps with any offset, or in the MySQL format, and responses always contain RFC 3339 timestamps in UTC with a
+//! `Z` suffix so that clients don't have to guess their time zone.
+
+use chrono::{DateTime, NaiveDateTime};
+use serde_json::Value;
+
+use super::MYSQL_DATE_FORMAT;
+
+/// Unix timestamps at least this large are interpreted as milliseconds.  In seconds, it lies in the year 5138.
+const MIN_MILLISECOND_TIMESTAMP: i64 = 100_000_000_000;
+
+/// The format of the timestamps in responses
+const UTC_FORMAT: &'static str = "%Y-%m-%dT%H:%M:%S%.fZ";
+
+/// Converts a Unix timestamp in seconds or milliseconds into a UTC timestamp.
+pub fn from_unix(timestamp: i64) -> Result<NaiveDateTime, String> {
+    let converted = if timestamp >= MIN_MILLISECOND_TIMESTAMP {
+        NaiveDateTime::from_timestamp_opt(timestamp / 1000, ((timestamp % 1000) * 1_000_000) as u32)
+    } else {
+        NaiveDateTime::from_timestamp_opt(timestamp, 0)
+    };
+
+    converted.ok_or(format!("Timestamp out of range: {}", timestamp))
+}
+
+/// Parses a timestamp supplied as a string in any of the accepted formats and converts it into UTC.  Timestamps without
+/// an offset are assumed to be in UTC.
+pub fn parse(timestamp: &str) -> Result<NaiveDateTime, String> {
+    let timestamp = timestamp.trim();
+    if let Ok(unix) = timestamp.parse::<i64>() {
+        return from_unix(unix);
+    }
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(timestamp) {
+        return Ok(datetime.naive_utc());
+    }
+
+    NaiveDateTime::parse_from_str(timestamp, MYSQL_DATE_FORMAT)
+        .or_else(|_| NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S%.f"))
+        .map_err(|_| format!("Invalid date supplied: {}", timestamp))
+}
+
+/// Parses a timestamp supplied as a JSON string or number.
+pub fn parse_value(timestamp: &Value) -> Result<NaiveDateTime, String> {
+    match *timestamp {
+        Value::String(ref timestamp) => parse(timestamp),
+        Value::Number(ref number) => match number.as_i64() {
+            Some(unix) => from_unix(unix),
+            None => Err(format!("Unix timestamps must be whole numbers: {}", number)),
+        },
+        _ => Err(format!("Invalid date supplied: {}", timestamp)),
+    }
+}
+
+/// Formats a UTC timestamp like "2017-01-01T00:00:00Z".
+pub fn format(timestamp: &NaiveDateTime) -> String {
+    timestamp.format(UTC_FORMAT).to_string()
+}
+
+/// (De)serializes a `NaiveDateTime` in UTC as an RFC 3339 timestamp with a `Z` suffix.  Use with
+/// `#[serde(with = "::timestamp::utc")]`.
+pub mod utc {
+    use chrono::NaiveDateTime;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::de::Error;
+
+    use super::{format, parse};
+
+    pub fn serialize<S: Serializer>(timestamp: &NaiveDateTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format(timestamp))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveDateTime, D::Error> {
+        parse(&String::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+/// Like `utc`, but for optional timestamps.  Use with `#[serde(with = "::timestamp::utc_option")]`.
+pub mod utc_option {
+    use chrono::NaiveDateTime;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::de::Error;
+
+    use super::{format, parse};
+
+    pub fn serialize<S: Serializer>(timestamp: &Option<NaiveDateTime>, serializer: S) -> Result<S::Ok, S::Error> {
+        match *timestamp {
+            Some(ref timestamp) => serializer.serialize_some(&format(timestamp)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<NaiveDateTime>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(timestamp) => parse(&timestamp).map(Some).map_err(D::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
+
+#[test]
+fn test_timestamp_parsing() {
+    let expected = NaiveDateTime::parse_from_str("2017-01-01 00:00:00", MYSQL_DATE_FORMAT).unwrap();
+    for timestamp in &["2017-01-01 00:00:00", "1483228800", "1483228800000", "2017-01-01T00:00:00Z",
+        "2017-01-01T03:00:00+03:00", "2016-12-31T19:00:00-05:00", "2017-01-01T00:00:00"]
+    {
+        assert_eq!(parse(timestamp), Ok(expected));
+    }
+    assert_eq!(parse_value(&Value::from(1483228800)), Ok(expected));
+    assert_eq!(parse("1483228800500").map(|timestamp| format(&timestamp)), Ok(String::from("2017-01-01T00:00:00.500Z")));
+    assert_eq!(format(&expected), "2017-01-01T00:00:00Z");
+
+    assert!(parse("2017-01-01").is_err());
+    assert!(parse("2014-01-25 05:44:38' OR '1'='1").is_err());
+    assert!(parse_value(&Value::from(1483228800.5)).is_err());
+    assert!(parse_value(&Value::Bool(true)).is_err());
+}
@@ -1,5 +1,5 @@
 //! Creates a detailed overview of currency changes with a chart showing when trades occured.  The chart will pull
-//! candlestick data from the Poloniex API and also show
+//! candlestick data from the internal API and also show
 /* global d3, techan */
 
 import React from 'react';
@@ -7,7 +7,7 @@ import ReactFauxDOM from 'react-faux-dom';
 import { connect } from 'dva';
 const _ = require('lodash');
 
-import { fetchPoloCandlestickData } from '../../utils/exchangeRates';
+import { fetchCandlestickData } from '../../utils/exchangeRates';
 import { batchFetchRates } from '../../utils/internalApi';
 
 /**
@@ -156,7 +156,7 @@ class CurrencyDrilldown extends React.Component {
     if(!poloRates || !cmcRates)
       return;
 
-    fetchPoloCandlestickData(pair, startTime, endTime, period).then(data => {
+    fetchCandlestickData(pair, startTime, endTime, period).then(data => {
       renderChart(
         data, this.container.offsetWidth, filteredTrades, currency, onTradeHover, onTradeUnhover,
         onTradeClick, poloRates, cmcRates, cachedRates, dispatch
@@ -164,7 +164,7 @@ class CurrencyDrilldown extends React.Component {
         this.setState({chartElem: chartElem});
       });
     }).catch(err => {
-      console.log('Error while fetching candlestick data from Poloniex API: ');
+      console.log('Error while fetching candlestick data from the internal API: ');
       console.log(err);
     });
   }
@@ -4,6 +4,8 @@ const TICKER_URL = 'https://blockchain.info/ticker?cors=true';
 const POLO_API_URL = 'https://poloniex.com/public';
 const COINMARKETCAP_URL = 'https://ameo.link/cmcapi/v1/ticker/';
 
+import { INTERNAL_API_URL } from '../conf';
+
 /**
  * Fetches the current exchange rate between BTC and the supplied base currency from the blockchain.info API.
  * Returns a promise that resolves to the supplied value when the request is complete.
@@ -67,13 +69,13 @@ function getCoinmarketcapRates() {
 }
 
 /**
- * Pulls Poloniex candlestick data from their API, maps it to the form that TechanJS expects, and returns a Promise that
- * yields the results once the process is complete.  Pair should be in a format like BTC_XMR and timestamps sould be
- * Unix timestamps with second precision.
+ * Pulls candlestick data built from our own stored trade data from the internal API, maps it to the form that TechanJS
+ * expects, and returns a Promise that yields the results once the process is complete.  Pair should be in a format like
+ * BTC_XMR and timestamps sould be Unix timestamps with second precision.
  */
-function fetchPoloCandlestickData(pair, startTime, endTime, period) {
+function fetchCandlestickData(pair, startTime, endTime, period) {
   return new Promise((f, r) => {
-    fetch(`${POLO_API_URL}?command=returnChartData&currencyPair=${pair}&start=${startTime}&end=${endTime}&period=${period}`)
+    fetch(`${INTERNAL_API_URL}/candles/${pair}?start=${startTime}&end=${endTime}&period=${period}`)
       .then(res => res.json())
       .then(body => {
         let mapped = _.map(body, candle => {
@@ -117,4 +119,4 @@ function getBtcValue(currency, amount, poloRates, coinmarketcapRates) {
   }
 }
 
-export { getBaseRate, listBaseCurrencies, getPoloRates, getBtcValue, getCoinmarketcapRates, fetchPoloCandlestickData };
+export { getBaseRate, listBaseCurrencies, getPoloRates, getBtcValue, getCoinmarketcapRates, fetchCandlestickData };
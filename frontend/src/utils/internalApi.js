@@ -32,13 +32,13 @@ function batchFetchRates(requests, poloRates, cmcRates, cachedRates, dispatch) {
         needsFetch.push(req);
     });
 
-    // map the dates to SQL format so they can be parsed by the API
+    // map the dates to ISO 8601 timestamps so they can be parsed by the API
     needsFetch = _.map(needsFetch, ({pair, date}) => {
       if(pair.includes('USDT')) {
         pair = 'BTC/USDT';
       }
       return {
-        date: new Date(date).toISOString().substring(0, 19).replace('T', ' '),
+        date: new Date(date).toISOString(),
         pair: pair,
       };
     });
@@ -68,7 +68,7 @@ function batchFetchRates(requests, poloRates, cmcRates, cachedRates, dispatch) {
           rate = getBtcValue(pair.split('/')[1], 1, poloRates, cmcRates);
         }
 
-        return {pair, rate, date: new Date(`${date}Z`), no_data, cached};
+        return {pair, rate, date: new Date(date), no_data, cached};
       });
 
       f(_.concat(mappedResults, cachedResults));